@@ -0,0 +1,5 @@
+// GSS-API comes from MIT krb5. It's linked by its soname, so building only
+// needs the runtime library (libgssapi-krb5-2 on Debian), not the -dev one.
+fn main() {
+    println!("cargo:rustc-link-lib=dylib:+verbatim=libgssapi_krb5.so.2");
+}
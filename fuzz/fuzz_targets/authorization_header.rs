@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+#[path = "../../src/credentials.rs"]
+mod credentials;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = credentials::parse_authorization_header(data);
+});
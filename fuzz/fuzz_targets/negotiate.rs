@@ -0,0 +1,48 @@
+//! The path an Authorization header takes through a worker thread: parsed,
+//! the token accepted on a fresh context and the result turned into the
+//! worker's reply. Without a keytab every context fails in the end, but
+//! only after the token got parsed.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+#[path = "../../src/credentials.rs"]
+mod credentials;
+#[allow(dead_code)]
+#[path = "../../src/delegation.rs"]
+mod delegation;
+#[allow(dead_code)]
+#[path = "../../src/gssapi.rs"]
+mod gssapi;
+#[allow(dead_code)]
+#[path = "../../src/gssapi_worker.rs"]
+mod gssapi_worker;
+#[allow(dead_code)]
+#[path = "../../src/identity.rs"]
+mod identity;
+
+use credentials::Credentials;
+use gssapi_worker::{BackendAuth, Msg, WorkerSettings};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some(Credentials::Negotiate(token))) = credentials::parse_authorization_header(data) {
+        let settings = WorkerSettings {
+            service_principal: None,
+            delegation_dir: None,
+            local_name: true,
+            name_rewrites: vec![],
+            keytab: None,
+            backend_auth: BackendAuth::None,
+            backend_principal: None,
+            required_flags: gssapi::ContextFlags::default(),
+        };
+        let mut context = gssapi::GSSContext::new();
+        let result =
+            gssapi::accept_sec_context(&mut context, None, &gssapi::AppBuffer::from(&token));
+        let _ = Msg::from(result, 0, &settings);
+    }
+});
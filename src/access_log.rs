@@ -0,0 +1,477 @@
+use chrono::{DateTime, Local};
+use futures::Poll;
+use hyper::body::Payload;
+use hyper::{Body, Chunk, Request};
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Layout of the access log lines. Common and Combined are the usual
+/// formats, the JSON one also has the upstream latency and how the
+/// client authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Common,
+    Combined,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "common" => Ok(Format::Common),
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            other => Err(format!(
+                "Invalid access log format {:?}, expected common, combined or json",
+                other
+            )),
+        }
+    }
+}
+
+/// How the request got authenticated, or why it didn't.
+/// Handlers attach it to the responses' extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthOutcome {
+    /// Public path, no authentication needed
+    Public,
+    /// Handshake finished with this request
+    Negotiate,
+    Basic,
+    /// Session resumed from a cookie
+    Cookie,
+    /// Already authenticated connection
+    Session,
+    /// Handshake needs another round
+    Continue,
+    /// No credentials, the client got a challenge
+    Challenge,
+    Failed,
+    TimedOut,
+    Overloaded,
+    /// Authenticated, but not allowed by the rules
+    Denied,
+    /// Authenticated, but the proxy couldn't authenticate to the backend
+    BackendFailed,
+}
+
+impl AuthOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthOutcome::Public => "public",
+            AuthOutcome::Negotiate => "negotiate",
+            AuthOutcome::Basic => "basic",
+            AuthOutcome::Cookie => "cookie",
+            AuthOutcome::Session => "session",
+            AuthOutcome::Continue => "continue",
+            AuthOutcome::Challenge => "challenge",
+            AuthOutcome::Failed => "failed",
+            AuthOutcome::TimedOut => "timed-out",
+            AuthOutcome::Overloaded => "overloaded",
+            AuthOutcome::Denied => "denied",
+            AuthOutcome::BackendFailed => "backend-failed",
+        }
+    }
+}
+
+/// Principal the request was made as, in the responses' extensions.
+#[derive(Debug, Clone)]
+pub struct Principal(pub String);
+
+/// Time until the upstream's response headers, in the responses' extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamLatency(pub Duration);
+
+/// One access log line, filled in as the request is handled.
+#[derive(Debug)]
+pub struct Entry {
+    pub time: DateTime<Local>,
+    pub peer: SocketAddr,
+    pub principal: Option<String>,
+    pub method: String,
+    pub uri: String,
+    pub version: http::Version,
+    pub status: u16,
+    pub bytes: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub upstream_latency: Option<Duration>,
+    pub auth: Option<AuthOutcome>,
+}
+
+impl Entry {
+    /// Start an entry for a request, before it's handled.
+    pub fn new<B>(req: &Request<B>, peer: SocketAddr) -> Entry {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        };
+        Entry {
+            time: Local::now(),
+            peer,
+            principal: None,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: req.version(),
+            status: 0,
+            bytes: 0,
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            upstream_latency: None,
+            auth: None,
+        }
+    }
+
+    fn format(&self, format: Format) -> String {
+        match format {
+            Format::Common => self.common(),
+            Format::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                escape(self.referer.as_ref().map_or("-", String::as_str)),
+                escape(self.user_agent.as_ref().map_or("-", String::as_str)),
+            ),
+            Format::Json => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        format!(
+            "{} - {} [{}] \"{} {} {:?}\" {} {}",
+            self.peer.ip(),
+            self.principal
+                .as_ref()
+                .map_or_else(|| String::from("-"), |p| escape(p).replace(' ', "\\x20")),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.method),
+            escape(&self.uri),
+            self.version,
+            self.status,
+            if self.bytes == 0 {
+                String::from("-")
+            } else {
+                self.bytes.to_string()
+            }
+        )
+    }
+
+    fn json(&self) -> String {
+        let mut line = String::from("{");
+        let mut field = |name: &str, value: String| {
+            if line.len() > 1 {
+                line.push(',');
+            }
+            let _ = write!(line, "{}:{}", json_string(name), value);
+        };
+        let string =
+            |s: Option<&String>| s.map_or_else(|| String::from("null"), |s| json_string(s));
+        field("time", json_string(&self.time.to_rfc3339()));
+        field("client", json_string(&self.peer.ip().to_string()));
+        field("user", string(self.principal.as_ref()));
+        field("method", json_string(&self.method));
+        field("uri", json_string(&self.uri));
+        field("protocol", json_string(&format!("{:?}", self.version)));
+        field("status", self.status.to_string());
+        field("bytes", self.bytes.to_string());
+        field("referer", string(self.referer.as_ref()));
+        field("user_agent", string(self.user_agent.as_ref()));
+        field(
+            "upstream_time",
+            self.upstream_latency.map_or_else(
+                || String::from("null"),
+                |d| format!("{}.{:03}", d.as_secs(), d.subsec_millis()),
+            ),
+        );
+        field(
+            "auth",
+            self.auth
+                .map_or_else(|| String::from("null"), |a| json_string(a.as_str())),
+        );
+        line.push('}');
+        line
+    }
+}
+
+// Keep client-controlled strings from breaking the line's format
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug)]
+enum Output {
+    Stderr,
+    File(PathBuf, File),
+}
+
+/// The access log, written to a file or (with `-` as the path) stderr.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: Format,
+    output: Mutex<Output>,
+}
+
+// Set by SIGHUP, the file gets reopened before the next line
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    REOPEN.store(true, Ordering::SeqCst);
+}
+
+/// Reopen the access log file on SIGHUP, for logrotate and the like.
+pub fn reopen_on_sighup() {
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, format: Format) -> io::Result<AccessLog> {
+        let output = if path == Path::new("-") {
+            Output::Stderr
+        } else {
+            Output::File(path.to_path_buf(), open_file(path)?)
+        };
+        Ok(AccessLog {
+            format,
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Whether the log goes to a file, which can be reopened.
+    pub fn is_file(&self) -> bool {
+        match *self.output.lock().unwrap() {
+            Output::File(..) => true,
+            Output::Stderr => false,
+        }
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        let mut output = self.output.lock().unwrap();
+        let result = match *output {
+            Output::Stderr => io::stderr().write_all(line.as_bytes()),
+            Output::File(ref path, ref mut file) => {
+                if REOPEN.swap(false, Ordering::SeqCst) {
+                    match open_file(path) {
+                        Ok(reopened) => *file = reopened,
+                        Err(e) => error!("Cannot reopen {}: {}", path.display(), e),
+                    }
+                }
+                file.write_all(line.as_bytes())
+            }
+        };
+        if let Err(e) = result {
+            error!("Cannot write the access log: {}", e);
+        }
+    }
+}
+
+/// Response body counting the bytes sent, which writes the access log
+/// entry when it's done with (sent completely, or the client went away).
+pub struct LoggedBody {
+    inner: Body,
+    entry: Option<(Entry, &'static AccessLog)>,
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, entry: Option<(Entry, &'static AccessLog)>) -> LoggedBody {
+        LoggedBody { inner, entry }
+    }
+}
+
+impl Payload for LoggedBody {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let data = self.inner.poll_data()?;
+        if let (futures::Async::Ready(Some(ref chunk)), Some((ref mut entry, _))) =
+            (&data, &mut self.entry)
+        {
+            entry.bytes += chunk.len() as u64;
+        }
+        Ok(data)
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, hyper::Error> {
+        self.inner.poll_trailers()
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.inner.content_length()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some((entry, log)) = self.entry.take() {
+            log.write(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        let request = Request::get("/wiki?page=Main")
+            .header(http::header::REFERER, "https://example.com/")
+            .header(http::header::USER_AGENT, "curl/7.64 \"quoted\"")
+            .body(())
+            .unwrap();
+        let mut entry = Entry::new(&request, "192.0.2.1:4321".parse().unwrap());
+        entry.time = DateTime::parse_from_rfc3339("2019-03-10T12:34:56+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+        entry.principal = Some(String::from("alice@EXAMPLE.COM"));
+        entry.status = 200;
+        entry.bytes = 1234;
+        entry.upstream_latency = Some(Duration::from_millis(1500));
+        entry.auth = Some(AuthOutcome::Negotiate);
+        entry
+    }
+
+    // The time as it's written in the common format, in the local timezone
+    fn common_time(entry: &Entry) -> String {
+        entry.time.format("%d/%b/%Y:%H:%M:%S %z").to_string()
+    }
+
+    #[test]
+    fn common() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(Format::Common),
+            format!(
+                "192.0.2.1 - alice@EXAMPLE.COM [{}] \"GET /wiki?page=Main HTTP/1.1\" 200 1234",
+                common_time(&entry)
+            )
+        );
+    }
+
+    #[test]
+    fn combined() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(Format::Combined),
+            format!(
+                "192.0.2.1 - alice@EXAMPLE.COM [{}] \"GET /wiki?page=Main HTTP/1.1\" 200 1234 \
+                 \"https://example.com/\" \"curl/7.64 \\\"quoted\\\"\"",
+                common_time(&entry)
+            )
+        );
+    }
+
+    #[test]
+    fn json() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(Format::Json),
+            format!(
+                "{{\"time\":\"{}\",\"client\":\"192.0.2.1\",\"user\":\"alice@EXAMPLE.COM\",\
+                 \"method\":\"GET\",\"uri\":\"/wiki?page=Main\",\"protocol\":\"HTTP/1.1\",\
+                 \"status\":200,\"bytes\":1234,\"referer\":\"https://example.com/\",\
+                 \"user_agent\":\"curl/7.64 \\\"quoted\\\"\",\"upstream_time\":1.500,\
+                 \"auth\":\"negotiate\"}}",
+                entry.time.to_rfc3339()
+            )
+        );
+    }
+
+    #[test]
+    fn missing_values() {
+        let request = Request::get("/").body(()).unwrap();
+        let entry = Entry::new(&request, "[2001:db8::1]:80".parse().unwrap());
+        let common = entry.format(Format::Common);
+        assert!(common.starts_with("2001:db8::1 - - ["), "{}", common);
+        // No bytes sent
+        assert!(common.ends_with("\"GET / HTTP/1.1\" 0 -"), "{}", common);
+        assert!(entry.format(Format::Combined).ends_with(" 0 - \"-\" \"-\""));
+        let json = entry.format(Format::Json);
+        assert!(json.contains("\"user\":null,"), "{}", json);
+        assert!(json.contains("\"bytes\":0,"), "{}", json);
+        assert!(
+            json.ends_with("\"upstream_time\":null,\"auth\":null}"),
+            "{}",
+            json
+        );
+    }
+
+    #[test]
+    fn principal_with_spaces() {
+        let mut entry = entry();
+        entry.principal = Some(String::from("Jane Doe@EXAMPLE.COM"));
+        assert!(entry
+            .format(Format::Common)
+            .contains(" - Jane\\x20Doe@EXAMPLE.COM ["));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape("plain / text"), "plain / text");
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(escape("a\nb\r\t\u{1}\u{7f}"), "a\\x0ab\\x0d\\x09\\x01\\x7f");
+        assert_eq!(escape("zażółć"), "zażółć");
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("a\nb\r\t"), "\"a\\nb\\r\\t\"");
+        assert_eq!(json_string("\u{0}\u{1f}"), "\"\\u0000\\u001f\"");
+        // Valid in JSON strings as they are
+        assert_eq!(json_string("\u{7f}zażółć"), "\"\u{7f}zażółć\"");
+    }
+}
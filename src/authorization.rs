@@ -0,0 +1,460 @@
+use super::identity::Identity;
+use http::header::{ACCESS_CONTROL_REQUEST_METHOD, ORIGIN};
+use http::{Method, Request};
+use regex::Regex;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug)]
+pub enum PrincipalMatcher {
+    Any,
+    Exact(String),
+    Realm(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+    Group(String),
+}
+
+impl PrincipalMatcher {
+    fn matches(&self, user: &Identity) -> bool {
+        let principal = user.principal.as_str();
+        match self {
+            PrincipalMatcher::Any => true,
+            PrincipalMatcher::Exact(name) => name == principal,
+            PrincipalMatcher::Realm(realm) => realm_of(principal) == Some(realm.as_str()),
+            PrincipalMatcher::Glob(pattern) => pattern.matches(principal),
+            PrincipalMatcher::Regex(re) => re.is_match(principal),
+            PrincipalMatcher::Group(group) => user.groups.contains(group),
+        }
+    }
+}
+
+impl FromStr for PrincipalMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PrincipalMatcher, String> {
+        if s == "*" {
+            Ok(PrincipalMatcher::Any)
+        } else if let Some(realm) = s.strip_prefix('@') {
+            Ok(PrincipalMatcher::Realm(String::from(realm)))
+        } else if let Some(group) = s.strip_prefix("group:").filter(|g| !g.is_empty()) {
+            Ok(PrincipalMatcher::Group(String::from(group)))
+        } else if let Some(regex) = s.strip_prefix("re:") {
+            // Anchor the expression, so it has to match the whole principal
+            Regex::new(&format!("^(?:{})$", regex))
+                .map(PrincipalMatcher::Regex)
+                .map_err(|e| format!("Invalid principal regex {:?}: {}", s, e))
+        } else if s.contains(['*', '?', '[']) {
+            glob::Pattern::new(s)
+                .map(PrincipalMatcher::Glob)
+                .map_err(|e| format!("Invalid principal glob {:?}: {}", s, e))
+        } else {
+            Ok(PrincipalMatcher::Exact(String::from(s)))
+        }
+    }
+}
+
+/// A single authorization rule.
+///
+/// Textual form is `ACTION PRINCIPAL [PATH_PREFIX [METHODS]]`, for example
+/// `allow @EXAMPLE.COM /admin GET,HEAD`. PRINCIPAL is `*`, `@REALM`,
+/// `re:REGEX`, `group:GROUP`, a glob (`*-admin@EXAMPLE.COM`) or an exact
+/// principal name.
+/// PATH_PREFIX and METHODS default to "everything" and can also be `*`.
+#[derive(Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub principal: PrincipalMatcher,
+    pub path_prefix: Option<String>,
+    pub methods: Option<Vec<Method>>,
+}
+
+impl Rule {
+    fn matches(&self, user: &Identity, method: &Method, path: &str) -> bool {
+        self.principal.matches(user)
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| path_has_prefix(path, prefix))
+            && self
+                .methods
+                .as_ref()
+                .is_none_or(|methods| methods.contains(method))
+    }
+
+    fn from_parts(
+        action: &str,
+        principal: &str,
+        path_prefix: Option<&str>,
+        methods: Option<&str>,
+    ) -> Result<Rule, String> {
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            other => return Err(format!("Invalid rule action {:?}", other)),
+        };
+        let principal = principal.parse()?;
+        let path_prefix = match path_prefix {
+            None | Some("*") => None,
+            Some(prefix) if prefix.starts_with('/') => Some(String::from(prefix)),
+            Some(prefix) => return Err(format!("Path prefix {:?} must start with /", prefix)),
+        };
+        let methods = match methods {
+            None | Some("*") => None,
+            Some(methods) => Some(parse_methods(methods)?),
+        };
+        Ok(Rule {
+            action,
+            principal,
+            path_prefix,
+            methods,
+        })
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rule, String> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() < 2 || parts.len() > 4 {
+            return Err(format!(
+                "Invalid rule {:?}, expected ACTION PRINCIPAL [PATH_PREFIX [METHODS]]",
+                s
+            ));
+        }
+        Rule::from_parts(
+            parts[0],
+            parts[1],
+            parts.get(2).cloned(),
+            parts.get(3).cloned(),
+        )
+    }
+}
+
+// Configuration file form of a rule:
+// { action = "allow", principal = "@EXAMPLE.COM", path_prefix = "/admin", methods = ["GET"] }
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    action: String,
+    principal: String,
+    path_prefix: Option<String>,
+    methods: Option<Vec<String>>,
+}
+
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rule, D::Error> {
+        let raw = RawRule::deserialize(deserializer)?;
+        let methods = raw.methods.map(|m| m.join(","));
+        Rule::from_parts(
+            &raw.action,
+            &raw.principal,
+            raw.path_prefix.as_deref(),
+            methods.as_deref(),
+        )
+        .map_err(D::Error::custom)
+    }
+}
+
+/// Evaluate the rules in order, the first matching one wins.
+/// Without any rules everyone is allowed, otherwise requests that don't
+/// match any rule are denied.
+pub fn is_allowed(rules: &[Rule], user: &Identity, method: &Method, path: &str) -> bool {
+    if rules.is_empty() {
+        return true;
+    }
+    rules
+        .iter()
+        .find(|r| r.matches(user, method, path))
+        .is_some_and(|r| r.action == Action::Allow)
+}
+
+/// Path pattern served without authentication, a glob like `/static/*`.
+/// Wildcards don't match `/`, `/static/**` covers the whole subtree.
+#[derive(Debug)]
+pub struct PublicPath(glob::Pattern);
+
+impl FromStr for PublicPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PublicPath, String> {
+        if !s.starts_with('/') {
+            return Err(format!("Public path {:?} must start with /", s));
+        }
+        glob::Pattern::new(s)
+            .map(PublicPath)
+            .map_err(|e| format!("Invalid public path pattern {:?}: {}", s, e))
+    }
+}
+
+impl PublicPath {
+    // `path` has to be normalized already, or `/static/../admin` would match
+    fn matches(&self, path: &str) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..glob::MatchOptions::new()
+        };
+        self.0.matches_with(path, &options)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PublicPath, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Whether the request can skip authentication: its path is public, or
+/// it's a CORS preflight (which browsers send without credentials) and
+/// those are allowed through.
+pub fn is_public<B>(public_paths: &[PublicPath], preflight: bool, req: &Request<B>) -> bool {
+    let is_preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    (preflight && is_preflight) || public_paths.iter().any(|p| p.matches(req.uri().path()))
+}
+
+fn parse_methods(raw: &str) -> Result<Vec<Method>, String> {
+    raw.split(',')
+        .map(|m| {
+            Method::from_bytes(m.to_uppercase().as_bytes())
+                .map_err(|_| format!("Invalid HTTP method {:?}", m))
+        })
+        .collect()
+}
+
+fn realm_of(principal: &str) -> Option<&str> {
+    principal.rfind('@').map(|i| &principal[i + 1..])
+}
+
+// "/admin" matches "/admin" and "/admin/users", but not "/administrator"
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    if prefix.ends_with('/') {
+        path.starts_with(prefix)
+    } else {
+        path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    }
+}
+
+/// Canonical form of a request path, what rules, public paths and routes
+/// are checked against and what the backend gets: percent-encoded
+/// unreserved characters are decoded, empty segments collapsed and dot
+/// segments resolved.
+/// Paths that can't be checked reliably (bad or encoded slash escapes)
+/// are rejected.
+pub fn normalize_path(path: &str) -> Result<String, String> {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(char::from(bytes[i]));
+            i += 1;
+            continue;
+        }
+        let byte = path
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("Invalid percent-encoding in {:?}", path))?;
+        match byte {
+            b'/' | b'\\' => return Err(format!("Encoded slash in {:?}", path)),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                decoded.push(char::from(byte))
+            }
+            _ => decoded.push_str(&format!("%{:02X}", byte)),
+        }
+        i += 3;
+    }
+
+    let mut segments: Vec<&str> = vec![];
+    let mut trailing_slash = false;
+    for segment in decoded.split('/') {
+        trailing_slash = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> Identity {
+        Identity {
+            principal: String::from("alice@EXAMPLE.COM"),
+            name: String::from("alice"),
+            groups: vec![String::from("admins")],
+            delegated: None,
+        }
+    }
+
+    fn allowed(rules: &[&str], method: Method, path: &str) -> bool {
+        let rules: Vec<Rule> = rules.iter().map(|r| r.parse().unwrap()).collect();
+        let path = match normalize_path(path) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        is_allowed(&rules, &alice(), &method, &path)
+    }
+
+    #[test]
+    fn rule_from_str() {
+        let rule: Rule = "allow @EXAMPLE.COM /admin GET,head".parse().unwrap();
+        assert_eq!(rule.action, Action::Allow);
+        assert!(rule.principal.matches(&alice()));
+        assert_eq!(rule.path_prefix.as_deref(), Some("/admin"));
+        assert_eq!(rule.methods, Some(vec![Method::GET, Method::HEAD]));
+
+        let rule: Rule = "deny * * *".parse().unwrap();
+        assert_eq!(rule.action, Action::Deny);
+        assert!(rule.path_prefix.is_none());
+        assert!(rule.methods.is_none());
+
+        assert!("allow group:admins"
+            .parse::<Rule>()
+            .unwrap()
+            .principal
+            .matches(&alice()));
+        assert!("allow re:ali.*"
+            .parse::<Rule>()
+            .unwrap()
+            .principal
+            .matches(&alice()));
+        assert!(!"allow re:ali"
+            .parse::<Rule>()
+            .unwrap()
+            .principal
+            .matches(&alice()));
+        assert!("allow a*@EXAMPLE.COM"
+            .parse::<Rule>()
+            .unwrap()
+            .principal
+            .matches(&alice()));
+        assert!(!"allow bob@EXAMPLE.COM"
+            .parse::<Rule>()
+            .unwrap()
+            .principal
+            .matches(&alice()));
+
+        assert!("allow".parse::<Rule>().is_err());
+        assert!("permit *".parse::<Rule>().is_err());
+        assert!("allow * admin".parse::<Rule>().is_err());
+        assert!("allow * /admin GET /extra".parse::<Rule>().is_err());
+        assert!("allow re:( /".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn prefix_matches_whole_segments() {
+        assert!(path_has_prefix("/admin", "/admin"));
+        assert!(path_has_prefix("/admin/users", "/admin"));
+        assert!(!path_has_prefix("/administrator", "/admin"));
+        assert!(path_has_prefix("/admin/users", "/admin/"));
+        assert!(!path_has_prefix("/admin", "/admin/"));
+        assert!(path_has_prefix("/anything", "/"));
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("/a/b/").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("//a///b").unwrap(), "/a/b");
+        assert_eq!(normalize_path("/a/./b/../c").unwrap(), "/a/c");
+        assert_eq!(normalize_path("/a/b/..").unwrap(), "/a/");
+        assert_eq!(normalize_path("/../../a").unwrap(), "/a");
+        assert_eq!(normalize_path("/%61%2D%7e").unwrap(), "/a-~");
+        assert_eq!(normalize_path("/%2e%2E/a").unwrap(), "/a");
+        assert_eq!(normalize_path("/a%20b%3f").unwrap(), "/a%20b%3F");
+        assert!(normalize_path("/a%2Fb").is_err());
+        assert!(normalize_path("/a%5cb").is_err());
+        assert!(normalize_path("/a%2").is_err());
+        assert!(normalize_path("/a%zz").is_err());
+    }
+
+    #[test]
+    fn denied_prefix_cannot_be_bypassed() {
+        let rules = ["deny * /admin", "allow *"];
+        assert!(allowed(&rules, Method::GET, "/public"));
+        for path in &[
+            "/admin",
+            "//admin",
+            "/./admin",
+            "/x/../admin",
+            "/%61dmin",
+            "/admin/",
+            "/admin/.",
+            "/public/../admin/users",
+            "/admin%2Fusers",
+        ] {
+            assert!(!allowed(&rules, Method::GET, path), "{} allowed", path);
+        }
+    }
+
+    #[test]
+    fn public_paths_match_normalized_segments() {
+        let public: Vec<PublicPath> =
+            vec!["/static/*".parse().unwrap(), "/docs/**".parse().unwrap()];
+        let is_public = |path: &str| {
+            let uri = normalize_path(path).unwrap();
+            is_public(&public, false, &Request::get(uri).body(()).unwrap())
+        };
+        assert!(is_public("/static/app.js"));
+        assert!(is_public("//static/./app.js"));
+        assert!(!is_public("/static/../admin"));
+        assert!(!is_public("/static/a/../../admin"));
+        assert!(!is_public("/static/js/app.js"));
+        assert!(!is_public("/staticfile"));
+        assert!(is_public("/docs/a/b/c.html"));
+        assert!(!is_public("/admin"));
+        assert!("static/*".parse::<PublicPath>().is_err());
+    }
+
+    #[test]
+    fn preflight_is_public_only_when_enabled() {
+        let preflight = Request::options("/api")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(())
+            .unwrap();
+        assert!(is_public(&[], true, &preflight));
+        assert!(!is_public(&[], false, &preflight));
+        let options = Request::options("/api").body(()).unwrap();
+        assert!(!is_public(&[], true, &options));
+    }
+
+    #[test]
+    fn rules_first_match_wins() {
+        assert!(allowed(&[], Method::DELETE, "/"));
+        let rules = [
+            "allow * /docs GET,HEAD",
+            "deny * /docs",
+            "allow @EXAMPLE.COM",
+        ];
+        assert!(allowed(&rules, Method::GET, "/docs/index"));
+        assert!(!allowed(&rules, Method::POST, "/docs/index"));
+        assert!(allowed(&rules, Method::POST, "/docsearch"));
+        assert!(!allowed(&["allow @OTHER.COM"], Method::GET, "/"));
+    }
+}
@@ -1,32 +1,923 @@
-use stderrlog;
+use super::access_log;
+use super::authorization::{PublicPath, Rule};
+use super::groups::LdapSettings;
+use super::gssapi::ContextFlags;
+use super::gssapi_worker::BackendAuth;
+use super::identity::Rewrite;
+use super::routing::Route;
+use super::upstream::{self, PoolSettings};
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
 
+/// Command line flags. Everything here is optional, so that values given
+/// in the configuration file are only overridden by flags that were
+/// actually passed.
 #[derive(Debug, StructOpt)]
-#[structopt(
-    name = "spnego-proxy",
-    about = "GSS-API based authentication proxy."
-)]
-pub struct Configuration {
+#[structopt(name = "spnego-proxy", about = "GSS-API based authentication proxy.")]
+struct CommandLine {
     #[structopt(
-        help = "Address to listen on",
-        default_value = "0.0.0.0:80",
-        long = "bind"
+        help = "Configuration file (TOML, or YAML with a .yaml/.yml extension)",
+        long = "config",
+        parse(from_os_str)
     )]
-    pub bind: String,
-    #[structopt(help = "Backend behind the proxy", long = "backend")]
-    pub backend: String,
+    config: Option<PathBuf>,
+
+    #[structopt(help = "Address to listen on [default: 0.0.0.0:80]", long = "bind")]
+    bind: Option<String>,
+    #[structopt(
+        help = "Backend behind the proxy, for requests no --route matched",
+        long = "backend"
+    )]
+    backend: Option<String>,
+    #[structopt(
+        help = "Route: \"[HOST]/PATH_PREFIX BACKEND [strip|NEW_PREFIX]\", or \
+                \"HOST BACKEND\". First matching route wins. \
+                Replaces the routes from the configuration file.",
+        long = "route",
+        raw(number_of_values = "1")
+    )]
+    routes: Vec<Route>,
 
     #[structopt(
         help = "Accept an invalid certificate from the backend",
-        long = "insecure",
+        long = "insecure"
     )]
-    pub tls_insecure: bool,
+    tls_insecure: bool,
+
+    #[structopt(
+        help = "Serve HTTPS with this certificate: PEM (with --tls-key) or PKCS#12",
+        long = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        help = "PEM encoded PKCS#8 private key for --tls-cert",
+        long = "tls-key",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(help = "Password for a PKCS#12 --tls-cert", long = "tls-password")]
+    tls_password: Option<String>,
+
+    #[structopt(
+        help = "Seconds a client has to finish the SPNEGO handshake, 0 disables [default: 30]",
+        long = "auth-timeout"
+    )]
+    auth_timeout: Option<u64>,
+    #[structopt(
+        help = "Seconds before an idle connection is closed, 0 disables [default: 75]",
+        long = "idle-timeout"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        help = "Seconds to wait for the backend's response, 0 disables [default: 60]",
+        long = "backend-timeout"
+    )]
+    backend_timeout: Option<u64>,
+    #[structopt(
+        help = "Seconds before an authenticated connection has to authenticate again, \
+                at most the lifetime of the client's ticket. 0 only limits it by the \
+                ticket [default: 0]",
+        long = "session-lifetime"
+    )]
+    session_lifetime: Option<u64>,
+
+    #[structopt(
+        help = "Number of GSS-API worker threads [default: 4]",
+        long = "gss-threads"
+    )]
+    gss_threads: Option<usize>,
+    #[structopt(
+        help = "Maximum number of handshakes in progress, 503 above that [default: 1024]",
+        long = "max-handshakes"
+    )]
+    max_handshakes: Option<usize>,
+    #[structopt(
+        help = "Maximum number of tokens waiting for each GSS-API thread [default: 64]",
+        long = "gss-queue"
+    )]
+    gss_queue: Option<usize>,
+
+    #[structopt(
+        help = "Issue a signed session cookie after authentication, so new \
+                connections don't need a new handshake",
+        long = "session-cookie"
+    )]
+    session_cookie: bool,
+    #[structopt(
+        help = "Session cookie name [default: spnego-proxy-session]",
+        long = "cookie-name"
+    )]
+    cookie_name: Option<String>,
+    #[structopt(
+        help = "Session cookie lifetime in seconds [default: 3600]",
+        long = "cookie-lifetime"
+    )]
+    cookie_lifetime: Option<u64>,
+    #[structopt(
+        help = "File with the session cookie signing key, share it between \
+                instances behind a load balancer (default: random key per process)",
+        long = "cookie-secret-file",
+        parse(from_os_str)
+    )]
+    cookie_secret_file: Option<PathBuf>,
+
+    #[structopt(
+        help = "Accept only this service principal, HTTP@app.example.com or \
+                HTTP/app.example.com[@REALM] (default: any principal from the keytab)",
+        long = "service-principal"
+    )]
+    service_principal: Option<String>,
+    #[structopt(
+        help = "Keytab with the service keys (default: KRB5_KTNAME)",
+        long = "keytab",
+        parse(from_os_str)
+    )]
+    keytab: Option<PathBuf>,
+
+    #[structopt(
+        help = "Store credentials delegated by clients in per-session ccaches in this directory",
+        long = "delegation-dir",
+        parse(from_os_str)
+    )]
+    delegation_dir: Option<PathBuf>,
+    #[structopt(
+        help = "Header used to pass the delegated credentials' ccache name to the backend \
+                [default: X-Remote-KRB5CCNAME]",
+        long = "delegation-header"
+    )]
+    delegation_header: Option<String>,
+
+    #[structopt(
+        help = "Also accept Basic authentication, checking the password with the KDC \
+                (requires TLS and --service-principal)",
+        long = "basic-auth"
+    )]
+    basic_auth: bool,
+    #[structopt(
+        help = "Realm of the Basic challenge [default: spnego-proxy]",
+        long = "basic-realm"
+    )]
+    basic_realm: Option<String>,
+
+    #[structopt(
+        help = "Authenticate to the backend with SPNEGO: none, delegated (with the \
+                client's delegated credentials) or impersonate (S4U2Self + S4U2Proxy) \
+                [default: none]",
+        long = "backend-auth"
+    )]
+    backend_auth: Option<BackendAuth>,
+    #[structopt(
+        help = "Backend's service principal, HTTP@backend.example.com or \
+                HTTP/backend.example.com[@REALM]",
+        long = "backend-principal"
+    )]
+    backend_principal: Option<String>,
+
+    #[structopt(
+        help = "Header used to pass the authenticated principal to the backend \
+                [default: X-Remote-User]",
+        long = "user-header"
+    )]
+    user_header: Option<String>,
+    #[structopt(
+        help = "Forward the local name of the principal, mapped with auth_to_local \
+                rules from krb5.conf",
+        long = "local-name"
+    )]
+    local_name: bool,
+    #[structopt(
+        help = "Rewrite of the forwarded name: strip-realm, lowercase or \
+                s/REGEX/REPLACEMENT/ (with \\/ for a /). Applied in order, after \
+                --local-name. Replaces the rewrites from the configuration file.",
+        long = "name-rewrite",
+        raw(number_of_values = "1")
+    )]
+    name_rewrites: Vec<Rewrite>,
+
+    #[structopt(
+        help = "Look up group membership: none, file, nss or ldap (configured in the \
+                [ldap] section of the configuration file) [default: none]",
+        long = "groups"
+    )]
+    groups: Option<GroupSource>,
+    #[structopt(
+        help = "File with \"GROUP: MEMBER...\" lines, for --groups file",
+        long = "groups-file",
+        parse(from_os_str)
+    )]
+    groups_file: Option<PathBuf>,
+    #[structopt(
+        help = "Seconds to cache group membership for [default: 300]",
+        long = "groups-ttl"
+    )]
+    groups_ttl: Option<u64>,
+    #[structopt(
+        help = "Header used to pass the user's groups to the backend \
+                [default: X-Remote-Groups]",
+        long = "groups-header"
+    )]
+    groups_header: Option<String>,
+
+    #[structopt(
+        help = "Authorization rule: \"allow|deny PRINCIPAL [PATH_PREFIX [METHODS]]\". \
+                PRINCIPAL is *, @REALM, re:REGEX, group:GROUP, a glob or an exact name. \
+                First matching rule wins, unmatched requests are denied. \
+                Replaces the rules from the configuration file.",
+        long = "rule",
+        raw(number_of_values = "1")
+    )]
+    rules: Vec<Rule>,
+    #[structopt(
+        help = "Path pattern (like /static/*) proxied without authentication. \
+                Replaces the public paths from the configuration file.",
+        long = "public-path",
+        raw(number_of_values = "1")
+    )]
+    public_paths: Vec<PublicPath>,
+    #[structopt(
+        help = "Proxy CORS preflight OPTIONS requests without authentication",
+        long = "public-preflight"
+    )]
+    public_preflight: bool,
+
+    #[structopt(
+        help = "Reject SPNEGO contexts without this flag: delegation, mutual, replay, \
+                sequence, confidentiality or integrity. Replaces the flags from the \
+                configuration file.",
+        long = "require-flag",
+        raw(number_of_values = "1")
+    )]
+    required_flags: Vec<ContextFlag>,
+
+    #[structopt(
+        help = "Address of a separate plain HTTP listener serving Prometheus \
+                metrics on /metrics",
+        long = "metrics-bind"
+    )]
+    metrics_bind: Option<String>,
+    #[structopt(
+        help = "Write an access log to this file, - for stderr. \
+                The file is reopened on SIGHUP.",
+        long = "access-log",
+        parse(from_os_str)
+    )]
+    access_log: Option<PathBuf>,
+    #[structopt(
+        help = "Access log format: common, combined or json [default: combined]",
+        long = "access-log-format"
+    )]
+    access_log_format: Option<access_log::Format>,
 
     // Logging {
     /// Verbose mode (-v, -vv, -vvv, etc)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
-    pub verbosity: usize,
+    verbosity: usize,
     /// Timestamp (sec, ms, ns, none)
     #[structopt(long = "log-timestamp")]
+    log_timestamp: Option<stderrlog::Timestamp>,
+    // }
+}
+
+/// Effective configuration, as read from the configuration file and then
+/// overridden by the command line.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Configuration {
+    pub bind: String,
+    pub backend: String,
+    pub routes: Vec<Route>,
+    pub pools: BTreeMap<String, PoolSettings>,
+    pub tls_insecure: bool,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_password: Option<String>,
+    pub auth_timeout: u64,
+    pub idle_timeout: u64,
+    pub backend_timeout: u64,
+    pub session_lifetime: u64,
+    pub gss_threads: usize,
+    pub max_handshakes: usize,
+    pub gss_queue: usize,
+    pub session_cookie: bool,
+    pub cookie_name: String,
+    pub cookie_lifetime: u64,
+    pub cookie_secret_file: Option<PathBuf>,
+    pub service_principal: Option<String>,
+    pub keytab: Option<PathBuf>,
+    pub delegation_dir: Option<PathBuf>,
+    pub delegation_header: String,
+    pub basic_auth: bool,
+    pub basic_realm: String,
+    pub backend_auth: BackendAuth,
+    pub backend_principal: Option<String>,
+    pub user_header: String,
+    pub local_name: bool,
+    pub name_rewrites: Vec<Rewrite>,
+    pub groups: GroupSource,
+    pub groups_file: Option<PathBuf>,
+    pub groups_ttl: u64,
+    pub groups_header: String,
+    pub ldap: Option<LdapSettings>,
+    pub rules: Vec<Rule>,
+    pub public_paths: Vec<PublicPath>,
+    pub public_preflight: bool,
+    pub required_flags: Vec<ContextFlag>,
+    pub access_log: Option<PathBuf>,
+    pub metrics_bind: Option<String>,
+    pub access_log_format: access_log::Format,
+
+    // Logging {
+    pub verbosity: usize,
+    #[serde(deserialize_with = "deserialize_from_str_opt")]
     pub log_timestamp: Option<stderrlog::Timestamp>,
     // }
 }
+
+impl Default for Configuration {
+    fn default() -> Configuration {
+        Configuration {
+            bind: String::from("0.0.0.0:80"),
+            backend: String::new(),
+            routes: vec![],
+            pools: BTreeMap::new(),
+            tls_insecure: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_password: None,
+            auth_timeout: 30,
+            idle_timeout: 75,
+            backend_timeout: 60,
+            session_lifetime: 0,
+            gss_threads: 4,
+            max_handshakes: 1024,
+            gss_queue: 64,
+            session_cookie: false,
+            cookie_name: String::from("spnego-proxy-session"),
+            cookie_lifetime: 3600,
+            cookie_secret_file: None,
+            service_principal: None,
+            keytab: None,
+            delegation_dir: None,
+            delegation_header: String::from("X-Remote-KRB5CCNAME"),
+            basic_auth: false,
+            basic_realm: String::from("spnego-proxy"),
+            backend_auth: BackendAuth::None,
+            backend_principal: None,
+            user_header: String::from("X-Remote-User"),
+            local_name: false,
+            name_rewrites: vec![],
+            groups: GroupSource::None,
+            groups_file: None,
+            groups_ttl: 300,
+            groups_header: String::from("X-Remote-Groups"),
+            ldap: None,
+            rules: vec![],
+            public_paths: vec![],
+            public_preflight: false,
+            required_flags: vec![],
+            access_log: None,
+            metrics_bind: None,
+            access_log_format: access_log::Format::Combined,
+            verbosity: 0,
+            log_timestamp: None,
+        }
+    }
+}
+
+/// All the problems found in the configuration, reported together.
+#[derive(Debug)]
+pub struct ConfigurationError(Vec<String>);
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for e in &self.0 {
+            writeln!(f, "  - {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! override_some {
+    ($conf:ident, $cli:ident, $($field:ident),*) => {
+        $(
+            if let Some(v) = $cli.$field {
+                $conf.$field = v;
+            }
+        )*
+    };
+}
+
+macro_rules! override_opt {
+    ($conf:ident, $cli:ident, $($field:ident),*) => {
+        $(
+            if $cli.$field.is_some() {
+                $conf.$field = $cli.$field;
+            }
+        )*
+    };
+}
+
+impl Configuration {
+    /// Parse the command line, read the configuration file it points to
+    /// (if any) and validate the result.
+    pub fn load() -> Result<Configuration, ConfigurationError> {
+        Configuration::from_command_line(CommandLine::from_args())
+    }
+
+    fn from_command_line(cli: CommandLine) -> Result<Configuration, ConfigurationError> {
+        let mut conf = match cli.config {
+            Some(ref path) => {
+                Configuration::from_file(path).map_err(|e| ConfigurationError(vec![e]))?
+            }
+            None => Configuration::default(),
+        };
+
+        override_some!(
+            conf,
+            cli,
+            bind,
+            backend,
+            auth_timeout,
+            idle_timeout,
+            backend_timeout,
+            session_lifetime,
+            gss_threads,
+            max_handshakes,
+            gss_queue,
+            cookie_name,
+            cookie_lifetime,
+            delegation_header,
+            basic_realm,
+            backend_auth,
+            groups,
+            groups_ttl,
+            groups_header,
+            user_header,
+            access_log_format
+        );
+        override_opt!(
+            conf,
+            cli,
+            tls_cert,
+            tls_key,
+            tls_password,
+            cookie_secret_file,
+            service_principal,
+            keytab,
+            delegation_dir,
+            backend_principal,
+            groups_file,
+            access_log,
+            metrics_bind,
+            log_timestamp
+        );
+        if cli.tls_insecure {
+            conf.tls_insecure = true;
+        }
+        if cli.session_cookie {
+            conf.session_cookie = true;
+        }
+        if cli.basic_auth {
+            conf.basic_auth = true;
+        }
+        if cli.local_name {
+            conf.local_name = true;
+        }
+        if !cli.name_rewrites.is_empty() {
+            conf.name_rewrites = cli.name_rewrites;
+        }
+        if !cli.routes.is_empty() {
+            conf.routes = cli.routes;
+        }
+        if !cli.rules.is_empty() {
+            conf.rules = cli.rules;
+        }
+        if !cli.public_paths.is_empty() {
+            conf.public_paths = cli.public_paths;
+        }
+        if cli.public_preflight {
+            conf.public_preflight = true;
+        }
+        if !cli.required_flags.is_empty() {
+            conf.required_flags = cli.required_flags;
+        }
+        if cli.verbosity > 0 {
+            conf.verbosity = cli.verbosity;
+        }
+
+        let errors = conf.validate();
+        if errors.is_empty() {
+            Ok(conf)
+        } else {
+            Err(ConfigurationError(errors))
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Configuration, String> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        let is_yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        if is_yaml {
+            serde_yaml::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))
+        } else {
+            toml::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if let Err(e) = self.bind.parse::<SocketAddr>() {
+            errors.push(format!("bind: invalid address {:?}: {}", self.bind, e));
+        }
+        if let Some(ref bind) = self.metrics_bind {
+            if let Err(e) = bind.parse::<SocketAddr>() {
+                errors.push(format!("metrics_bind: invalid address {:?}: {}", bind, e));
+            }
+        }
+        if self.backend.is_empty() && self.routes.is_empty() {
+            errors.push(String::from(
+                "backend: missing, use --backend, --route or the configuration file",
+            ));
+        } else if !self.backend.is_empty() && upstream::pool_name(&self.backend).is_none() {
+            if let Err(e) = upstream::check_url(&self.backend) {
+                errors.push(format!("backend: {}", e));
+            }
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            for e in route.validate() {
+                errors.push(format!("routes[{}]: {}", i, e));
+            }
+        }
+        for (name, pool) in &self.pools {
+            for e in pool.validate() {
+                errors.push(format!("pools.{}: {}", name, e));
+            }
+        }
+        let backends = self
+            .routes
+            .iter()
+            .map(|r| &r.backend)
+            .chain(Some(&self.backend));
+        for name in backends.filter_map(|b| upstream::pool_name(b)) {
+            if !self.pools.contains_key(name) {
+                errors.push(format!("backend: no pool named {:?}", name));
+            }
+        }
+        if self.gss_threads == 0 {
+            errors.push(String::from("gss_threads: needs at least one thread"));
+        }
+        if self.max_handshakes == 0 {
+            errors.push(String::from("max_handshakes: must be positive"));
+        }
+        if self.gss_queue == 0 {
+            errors.push(String::from("gss_queue: must be positive"));
+        }
+        if self.cookie_name.is_empty()
+            || !self
+                .cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            errors.push(format!("cookie_name: invalid name {:?}", self.cookie_name));
+        }
+        if self.cookie_lifetime == 0 {
+            errors.push(String::from("cookie_lifetime: must be positive"));
+        }
+        if self.tls_key.is_some() && self.tls_cert.is_none() {
+            errors.push(String::from("tls_key: requires tls_cert"));
+        }
+        if self.tls_key.is_some() && self.tls_password.is_some() {
+            errors.push(String::from(
+                "tls_password: only used with a PKCS#12 tls_cert, not with tls_key",
+            ));
+        }
+        if let Err(e) = self.delegation_header.parse::<http::header::HeaderName>() {
+            errors.push(format!(
+                "delegation_header: invalid header name {:?}: {}",
+                self.delegation_header, e
+            ));
+        }
+        if self.basic_auth && self.tls_cert.is_none() {
+            errors.push(String::from(
+                "basic_auth: only allowed with TLS, passwords would be sent in clear text",
+            ));
+        }
+        if self.basic_auth && self.service_principal.is_none() {
+            errors.push(String::from("basic_auth: requires service_principal"));
+        }
+        if self
+            .basic_realm
+            .contains(|c: char| c == '"' || c == '\\' || c.is_control())
+        {
+            errors.push(format!("basic_realm: invalid realm {:?}", self.basic_realm));
+        }
+        if self.backend_auth != BackendAuth::None && self.backend_principal.is_none() {
+            errors.push(String::from("backend_auth: requires backend_principal"));
+        }
+        if self.backend_auth == BackendAuth::Delegated && self.delegation_dir.is_none() {
+            errors.push(String::from(
+                "backend_auth: delegated requires delegation_dir",
+            ));
+        }
+        if self.backend_auth == BackendAuth::Impersonate && self.service_principal.is_none() {
+            errors.push(String::from(
+                "backend_auth: impersonate requires service_principal",
+            ));
+        }
+        if self.groups == GroupSource::File && self.groups_file.is_none() {
+            errors.push(String::from("groups: file requires groups_file"));
+        }
+        if self.groups == GroupSource::Ldap {
+            match self.ldap {
+                None => errors.push(String::from("groups: ldap requires an [ldap] section")),
+                Some(ref ldap) if ldap.url.is_empty() || ldap.base.is_empty() => {
+                    errors.push(String::from("ldap: url and base are required"))
+                }
+                Some(_) => (),
+            }
+        }
+        if let Err(e) = self.groups_header.parse::<http::header::HeaderName>() {
+            errors.push(format!(
+                "groups_header: invalid header name {:?}: {}",
+                self.groups_header, e
+            ));
+        }
+        if let Err(e) = self.user_header.parse::<http::header::HeaderName>() {
+            errors.push(format!(
+                "user_header: invalid header name {:?}: {}",
+                self.user_header, e
+            ));
+        }
+        errors
+    }
+}
+
+/// Context flag a client's SPNEGO context must have.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextFlag {
+    Delegation,
+    Mutual,
+    Replay,
+    Sequence,
+    Confidentiality,
+    Integrity,
+}
+
+impl FromStr for ContextFlag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ContextFlag, String> {
+        match s {
+            "delegation" => Ok(ContextFlag::Delegation),
+            "mutual" => Ok(ContextFlag::Mutual),
+            "replay" => Ok(ContextFlag::Replay),
+            "sequence" => Ok(ContextFlag::Sequence),
+            "confidentiality" => Ok(ContextFlag::Confidentiality),
+            "integrity" => Ok(ContextFlag::Integrity),
+            other => Err(format!(
+                "Invalid context flag {:?}, expected delegation, mutual, replay, sequence, \
+                 confidentiality or integrity",
+                other
+            )),
+        }
+    }
+}
+
+/// The GSS-API flags for a list of required flags.
+pub fn context_flags(flags: &[ContextFlag]) -> ContextFlags {
+    flags.iter().fold(ContextFlags::default(), |all, flag| {
+        all.union(match flag {
+            ContextFlag::Delegation => ContextFlags::DELEGATION,
+            ContextFlag::Mutual => ContextFlags::MUTUAL,
+            ContextFlag::Replay => ContextFlags::REPLAY,
+            ContextFlag::Sequence => ContextFlags::SEQUENCE,
+            ContextFlag::Confidentiality => ContextFlags::CONFIDENTIALITY,
+            ContextFlag::Integrity => ContextFlags::INTEGRITY,
+        })
+    })
+}
+
+/// Where group membership comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupSource {
+    None,
+    File,
+    Nss,
+    Ldap,
+}
+
+impl FromStr for GroupSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GroupSource, String> {
+        match s {
+            "none" => Ok(GroupSource::None),
+            "file" => Ok(GroupSource::File),
+            "nss" => Ok(GroupSource::Nss),
+            "ldap" => Ok(GroupSource::Ldap),
+            other => Err(format!(
+                "Invalid group source {:?}, expected none, file, nss or ldap",
+                other
+            )),
+        }
+    }
+}
+
+/// Timeouts are configured in seconds, with 0 meaning "no timeout".
+pub fn timeout(seconds: u64) -> Option<Duration> {
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+fn deserialize_from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(raw) => raw.parse().map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "spnego-proxy-config-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    // Configuration from `file` (if any) and the command line `args`
+    fn load(file: Option<(&str, &str)>, args: &[&str]) -> Result<Configuration, Vec<String>> {
+        let path = file.map(|(name, contents)| config_file(name, contents));
+        let mut all_args = vec!["spnego-proxy"];
+        if let Some(ref path) = path {
+            all_args.push("--config");
+            all_args.push(path.to_str().unwrap());
+        }
+        all_args.extend(args);
+        let conf = Configuration::from_command_line(CommandLine::from_iter(all_args));
+        if let Some(path) = path {
+            fs::remove_file(path).unwrap();
+        }
+        conf.map_err(|e| e.0)
+    }
+
+    const FILE: &str = r#"
+        bind = "127.0.0.1:8080"
+        backend = "http://backend:80"
+        auth_timeout = 10
+        gss_threads = 2
+        keytab = "/etc/file.keytab"
+        service_principal = "HTTP@proxy"
+        public_paths = ["/static/*", "/favicon.ico"]
+
+        [[routes]]
+        path_prefix = "/api"
+        backend = "http://api:80"
+
+        [[routes]]
+        host = "wiki.example.com"
+        backend = "http://wiki:80"
+    "#;
+
+    #[test]
+    fn defaults_without_file() {
+        let conf = load(None, &["--backend", "http://backend:80"]).unwrap();
+        assert_eq!(conf.bind, "0.0.0.0:80");
+        assert_eq!(conf.auth_timeout, 30);
+        assert_eq!(conf.max_handshakes, 1024);
+        assert!(conf.keytab.is_none());
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let conf = load(Some(("some.toml", FILE)), &["--auth-timeout", "5"]).unwrap();
+        assert_eq!(conf.auth_timeout, 5);
+        // Not passed, so from the file, or the default
+        assert_eq!(conf.bind, "127.0.0.1:8080");
+        assert_eq!(conf.gss_threads, 2);
+        assert_eq!(conf.idle_timeout, 75);
+    }
+
+    #[test]
+    fn command_line_overrides_optional_values() {
+        let conf = load(Some(("opt.toml", FILE)), &["--keytab", "/etc/cli.keytab"]).unwrap();
+        assert_eq!(conf.keytab, Some(PathBuf::from("/etc/cli.keytab")));
+        assert_eq!(conf.service_principal.as_deref(), Some("HTTP@proxy"));
+        assert!(conf.tls_cert.is_none());
+    }
+
+    #[test]
+    fn command_line_lists_replace_file_lists() {
+        let conf = load(Some(("lists.toml", FILE)), &[]).unwrap();
+        assert_eq!(conf.routes.len(), 2);
+        assert_eq!(conf.routes[1].host.as_deref(), Some("wiki.example.com"));
+
+        let conf = load(
+            Some(("lists-cli.toml", FILE)),
+            &[
+                "--route",
+                "/other http://other:80",
+                "--public-path",
+                "/health",
+            ],
+        )
+        .unwrap();
+        assert_eq!(conf.routes.len(), 1);
+        assert_eq!(conf.routes[0].backend, "http://other:80");
+        assert_eq!(conf.public_paths.len(), 1);
+    }
+
+    #[test]
+    fn yaml_by_extension() {
+        let yaml = "bind: 127.0.0.1:9090\nbackend: http://backend:80\nroutes:\n  - path_prefix: /api\n    backend: http://api:80\n";
+        for name in &["conf.yaml", "conf.yml"] {
+            let conf = load(Some((name, yaml)), &[]).unwrap();
+            assert_eq!(conf.bind, "127.0.0.1:9090");
+            assert_eq!(conf.routes[0].path_prefix.as_deref(), Some("/api"));
+        }
+        // Anything else is TOML
+        assert!(load(Some(("conf.toml", yaml)), &[]).is_err());
+        assert!(load(Some(("conf", yaml)), &[]).is_err());
+        assert!(load(Some(("yaml.conf", FILE)), &[]).is_ok());
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let errors = load(
+            Some((
+                "unknown.toml",
+                "backend = \"http://b:80\"\nbnd = \"0.0.0.0:1\"\n",
+            )),
+            &[],
+        )
+        .unwrap_err();
+        assert!(errors[0].contains("unknown field `bnd`"), "{:?}", errors);
+
+        let nested =
+            "backend = \"http://b:80\"\n[[routes]]\nbackend = \"http://a:80\"\nprefix = \"/a\"\n";
+        let errors = load(Some(("nested.toml", nested)), &[]).unwrap_err();
+        assert!(errors[0].contains("unknown field `prefix`"), "{:?}", errors);
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let errors = load(
+            None,
+            &[
+                "--bind",
+                "nowhere",
+                "--gss-threads",
+                "0",
+                "--max-handshakes",
+                "0",
+                "--gss-queue",
+                "0",
+                "--cookie-lifetime",
+                "0",
+            ],
+        )
+        .unwrap_err();
+        for field in &[
+            "bind:",
+            "backend:",
+            "gss_threads:",
+            "max_handshakes:",
+            "gss_queue:",
+            "cookie_lifetime:",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.starts_with(field)),
+                "no {} in {:?}",
+                field,
+                errors
+            );
+        }
+        assert_eq!(errors.len(), 6, "{:?}", errors);
+    }
+}
@@ -0,0 +1,96 @@
+/// What the client sent in its `Authorization` header.
+#[derive(PartialEq)]
+pub enum Credentials {
+    /// SPNEGO token
+    Negotiate(Vec<u8>),
+    /// User name and password
+    Basic(String, String),
+}
+
+// Don't leak passwords into the logs
+impl ::std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            Credentials::Negotiate(token) => write!(f, "Negotiate({:?})", token),
+            Credentials::Basic(user, _) => write!(f, "Basic({:?}, ...)", user),
+        }
+    }
+}
+
+/// Parse an `Authorization` header value. Unknown schemes are treated as
+/// no credentials at all, malformed values of the ones we handle are errors.
+pub fn parse_authorization_header(raw: &[u8]) -> Result<Option<Credentials>, String> {
+    let raw = ::std::str::from_utf8(raw).map_err(|_| String::from("not valid UTF-8"))?;
+    let mut parts = raw.trim().splitn(2, ' ');
+    let scheme = parts.next().unwrap_or("");
+    let value = parts.next().unwrap_or("").trim();
+    if scheme.eq_ignore_ascii_case("Negotiate") {
+        if value.is_empty() {
+            return Err(String::from("empty Negotiate token"));
+        }
+        base64::decode(value)
+            .map(|token| Some(Credentials::Negotiate(token)))
+            .map_err(|e| format!("invalid Negotiate token: {}", e))
+    } else if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = base64::decode(value).map_err(|e| format!("invalid Basic value: {}", e))?;
+        let decoded = String::from_utf8(decoded)
+            .map_err(|_| String::from("Basic credentials aren't valid UTF-8"))?;
+        let mut parts = decoded.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(user), Some(password)) if !user.is_empty() => Ok(Some(Credentials::Basic(
+                String::from(user),
+                String::from(password),
+            ))),
+            _ => Err(String::from("Basic credentials without a user name")),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<Credentials>, String> {
+        parse_authorization_header(raw.as_bytes())
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(
+            parse("Negotiate YIIB"),
+            Ok(Some(Credentials::Negotiate(vec![0x60, 0x82, 0x01])))
+        );
+        assert_eq!(
+            parse("negotiate  YIIB "),
+            Ok(Some(Credentials::Negotiate(vec![0x60, 0x82, 0x01])))
+        );
+        assert!(parse("Negotiate").is_err());
+        assert!(parse("Negotiate ").is_err());
+        assert!(parse("Negotiate not-base64!").is_err());
+    }
+
+    #[test]
+    fn basic() {
+        // alice:pass:word
+        assert_eq!(
+            parse("Basic YWxpY2U6cGFzczp3b3Jk"),
+            Ok(Some(Credentials::Basic(
+                String::from("alice"),
+                String::from("pass:word")
+            )))
+        );
+        // :password
+        assert!(parse("Basic OnBhc3N3b3Jk").is_err());
+        // alice
+        assert!(parse("Basic YWxpY2U=").is_err());
+    }
+
+    #[test]
+    fn other_schemes() {
+        assert_eq!(parse("Bearer abc"), Ok(None));
+        assert_eq!(parse(""), Ok(None));
+        assert!(parse_authorization_header(&[0xff, 0xfe]).is_err());
+    }
+}
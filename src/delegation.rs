@@ -0,0 +1,168 @@
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Path of the ccache holding the credentials `principal` delegated in
+/// `session` (unique within this process).
+///
+/// Anything but ASCII letters, digits, `.` and `-` is hex-escaped, so
+/// principal names can't collide or escape the directory.
+pub fn ccache_path(dir: &Path, principal: &str, session: usize) -> PathBuf {
+    let mut name = String::from("krb5cc_");
+    for b in principal.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'.' || b == b'-' {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("_{:02x}", b));
+        }
+    }
+    // `+` is always escaped in the principal part
+    name.push_str(&format!("+{}.{}", process::id(), session));
+    dir.join(name)
+}
+
+/// Delegated credentials stored for a session. The ccache is removed
+/// once the last reference is gone, when the session ends or expires.
+#[derive(Debug, PartialEq)]
+pub struct Ccache(PathBuf);
+
+impl Ccache {
+    pub fn new(path: PathBuf) -> Ccache {
+        Ccache(path)
+    }
+
+    /// The ccache name, as used in KRB5CCNAME.
+    pub fn name(&self) -> String {
+        ccache_name(&self.0)
+    }
+}
+
+impl Drop for Ccache {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.0) {
+            Ok(()) => debug!("Removed {:?}", self.0),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Cannot remove {:?}: {}", self.0, e),
+        }
+    }
+}
+
+/// The ccache name, as used in KRB5CCNAME.
+pub fn ccache_name(path: &Path) -> String {
+    format!("FILE:{}", path.display())
+}
+
+/// Remove ccaches left behind by proxy processes that are gone (crashed,
+/// most likely), and by an earlier process with our pid. Other files and
+/// other running proxies' ccaches are left alone. Returns how many were
+/// removed.
+pub fn remove_stale(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let stale = match entry.file_name().to_str().and_then(owner_pid) {
+            Some(pid) => pid == process::id() || !is_running(pid),
+            None => false,
+        };
+        if stale {
+            fs::remove_file(entry.path())?;
+            debug!("Removed stale {:?}", entry.path());
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Pid of the process that stored a ccache named by `ccache_path`
+fn owner_pid(name: &str) -> Option<u32> {
+    let (_, suffix) = name.strip_prefix("krb5cc_")?.rsplit_once('+')?;
+    let (pid, session) = suffix.split_once('.')?;
+    session.parse::<usize>().ok()?;
+    pid.parse().ok()
+}
+
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists
+    let signalled = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Create the ccache directory, readable only by the proxy's user.
+pub fn create_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_name(principal: &str) -> String {
+        let path = ccache_path(Path::new("/var/lib/ccaches"), principal, 7);
+        assert_eq!(path.parent(), Some(Path::new("/var/lib/ccaches")));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let suffix = format!("+{}.7", process::id());
+        String::from(name.strip_suffix(&suffix).unwrap())
+    }
+
+    #[test]
+    fn escapes_principals() {
+        assert_eq!(file_name("alice@EXAMPLE.COM"), "krb5cc_alice_40EXAMPLE.COM");
+        assert_eq!(file_name("../x@R"), "krb5cc_.._2fx_40R");
+        assert_eq!(file_name("a/b@R"), "krb5cc_a_2fb_40R");
+        assert_eq!(file_name("..\\x@R"), "krb5cc_.._5cx_40R");
+        assert_eq!(file_name("ł+1.2@R"), "krb5cc__c5_82_2b1.2_40R");
+        assert_eq!(file_name(""), "krb5cc_");
+    }
+
+    #[test]
+    fn escaping_avoids_collisions() {
+        let principals = ["a/b@R", "a_2fb@R", "a_b@R", "a b@R", "a+b@R", "a_2bb@R"];
+        let mut names: Vec<String> = principals.iter().map(|p| file_name(p)).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), principals.len(), "{:?}", names);
+    }
+
+    #[test]
+    fn sessions_get_their_own_ccaches() {
+        let dir = Path::new("/tmp");
+        assert_ne!(
+            ccache_path(dir, "alice@R", 1),
+            ccache_path(dir, "alice@R", 2)
+        );
+        assert_eq!(
+            owner_pid(
+                ccache_path(dir, "a+b@R", 3)
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+            ),
+            Some(process::id())
+        );
+    }
+
+    #[test]
+    fn removes_stale_ccaches() {
+        let dir = std::env::temp_dir().join(format!("spnego-proxy-ccaches-{}", process::id()));
+        create_dir(&dir).unwrap();
+        let ours = ccache_path(&dir, "alice@R", 1);
+        let running = dir.join("krb5cc_bob_40R+1.1");
+        // Past the largest pid Linux hands out
+        let gone = dir.join("krb5cc_carol_40R+4194305.2");
+        let other = dir.join("krb5cc_0");
+        for path in &[&ours, &running, &gone, &other] {
+            fs::write(path, "").unwrap();
+        }
+        assert_eq!(remove_stale(&dir).unwrap(), 2);
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        left.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(left, vec![other, running]);
+    }
+}
@@ -0,0 +1,219 @@
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::net::IpAddr;
+
+// Headers that only make sense for a single connection (RFC 7230 section
+// 6.1), plus the non-standard ones still seen in the wild
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove the hop-by-hop headers, including the ones listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Remove `name` along with its spellings using `_` for `-`, like
+/// `X_Remote_User`, which CGI-style backends can't tell apart from it.
+pub fn strip_with_variants(headers: &mut HeaderMap, name: &HeaderName) {
+    let canonical = name.as_str().replace('_', "-");
+    let variants: Vec<HeaderName> = headers
+        .keys()
+        .filter(|h| h.as_str().replace('_', "-") == canonical)
+        .cloned()
+        .collect();
+    for variant in variants {
+        headers.remove(variant);
+    }
+}
+
+/// Tell the backend who it's really talking to: append the client to
+/// `X-Forwarded-For` and `Forwarded`, and set `X-Forwarded-Proto` and
+/// `X-Forwarded-Host`. `host` is the `Host` the client asked for.
+pub fn add_forwarding_headers(
+    headers: &mut HeaderMap,
+    client: Option<IpAddr>,
+    proto: &str,
+    host: Option<&str>,
+) {
+    let client_name = client.map_or_else(|| String::from("unknown"), |ip| ip.to_string());
+    append(headers, "x-forwarded-for", &client_name);
+
+    let for_node = match client {
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        None => String::from("unknown"),
+    };
+    let mut forwarded = format!("for={}", for_node);
+    if let Some(host) = host {
+        forwarded.push_str(&format!(";host={}", quote_if_needed(host)));
+    }
+    forwarded.push_str(&format!(";proto={}", proto));
+    append(headers, "forwarded", &forwarded);
+
+    headers.insert("x-forwarded-proto", HeaderValue::from_str(proto).unwrap());
+    match host.map(HeaderValue::from_str) {
+        Some(Ok(val)) => {
+            headers.insert("x-forwarded-host", val);
+        }
+        _ => {
+            headers.remove("x-forwarded-host");
+        }
+    }
+}
+
+// Add to the comma separated list in the header, keeping earlier proxies
+fn append(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let previous: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    let value = if previous.is_empty() {
+        String::from(value)
+    } else {
+        format!("{}, {}", previous.join(", "), value)
+    };
+    match HeaderValue::from_str(&value) {
+        Ok(val) => {
+            headers.insert(name, val);
+        }
+        Err(_) => warn!("Cannot set {} to {:?}", name, value),
+    }
+}
+
+// RFC 7239 values are tokens or quoted strings, and a host:port isn't a token
+fn quote_if_needed(value: &str) -> String {
+    let is_token = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        String::from(value)
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|v| String::from(v.to_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut h = headers(&[
+            ("connection", "keep-alive, X-Private"),
+            ("connection", "x-other"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("upgrade", "websocket"),
+            ("x-private", "secret"),
+            ("x-other", "1"),
+            ("x-kept", "1"),
+            ("authorization", "Bearer token"),
+        ]);
+        strip_hop_by_hop(&mut h);
+        let mut names: Vec<&str> = h.keys().map(HeaderName::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["authorization", "x-kept"]);
+    }
+
+    #[test]
+    fn strips_underscore_variants() {
+        let mut h = headers(&[
+            ("x-remote-user", "mallory"),
+            ("X_Remote_User", "mallory"),
+            ("x-remote_user", "mallory"),
+            ("x-remote-users", "kept"),
+            ("xremote-user", "kept"),
+        ]);
+        strip_with_variants(&mut h, &HeaderName::from_static("x-remote-user"));
+        let mut names: Vec<&str> = h.keys().map(HeaderName::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["x-remote-users", "xremote-user"]);
+    }
+
+    #[test]
+    fn appends_to_forwarding_headers() {
+        let mut h = headers(&[
+            ("x-forwarded-for", "10.0.0.1"),
+            ("forwarded", "for=10.0.0.1;proto=https"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        add_forwarding_headers(
+            &mut h,
+            Some([192, 0, 2, 7].into()),
+            "http",
+            Some("example.com"),
+        );
+        assert_eq!(values(&h, "x-forwarded-for"), vec!["10.0.0.1, 192.0.2.7"]);
+        assert_eq!(
+            values(&h, "forwarded"),
+            vec!["for=10.0.0.1;proto=https, for=192.0.2.7;host=example.com;proto=http"]
+        );
+        assert_eq!(values(&h, "x-forwarded-proto"), vec!["http"]);
+        assert_eq!(values(&h, "x-forwarded-host"), vec!["example.com"]);
+    }
+
+    #[test]
+    fn quotes_forwarded_values() {
+        let mut h = HeaderMap::new();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        add_forwarding_headers(&mut h, Some(ip), "https", Some("example.com:8443"));
+        assert_eq!(values(&h, "x-forwarded-for"), vec!["2001:db8::1"]);
+        assert_eq!(
+            values(&h, "forwarded"),
+            vec!["for=\"[2001:db8::1]\";host=\"example.com:8443\";proto=https"]
+        );
+    }
+
+    #[test]
+    fn unknown_client_and_host() {
+        let mut h = headers(&[("x-forwarded-host", "spoofed.example.com")]);
+        add_forwarding_headers(&mut h, None, "http", None);
+        assert_eq!(values(&h, "x-forwarded-for"), vec!["unknown"]);
+        assert_eq!(values(&h, "forwarded"), vec!["for=unknown;proto=http"]);
+        assert!(h.get("x-forwarded-host").is_none());
+    }
+
+    #[test]
+    fn quote_escapes() {
+        assert_eq!(quote_if_needed("example.com"), "example.com");
+        assert_eq!(quote_if_needed("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+}
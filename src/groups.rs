@@ -0,0 +1,591 @@
+use super::configuration;
+use super::identity::Identity;
+use ldap3::{LdapConn, LdapConnSettings, Scope, SearchEntry};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of group membership. Lookups may block, so they shouldn't be
+/// called directly from the event loop.
+pub trait GroupResolver: fmt::Debug + Send + Sync {
+    fn groups(&self, identity: &Identity) -> Result<Vec<String>, String>;
+}
+
+/// Groups listed in a file, one group per line:
+///
+/// ```text
+/// # group: members
+/// admins: alice@EXAMPLE.COM bob@EXAMPLE.COM
+/// ops: carol
+/// ```
+///
+/// Members can be given either as principals or as forwarded names.
+#[derive(Debug)]
+pub struct StaticGroups {
+    members: HashMap<String, Vec<String>>,
+}
+
+impl StaticGroups {
+    pub fn load(path: &Path) -> Result<StaticGroups, String> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        let mut members: HashMap<String, Vec<String>> = HashMap::new();
+        for (n, line) in raw.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(group), Some(users)) if !group.trim().is_empty() => {
+                    for user in users.split_whitespace() {
+                        members
+                            .entry(String::from(user))
+                            .or_default()
+                            .push(String::from(group.trim()));
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "{}:{}: expected \"GROUP: MEMBER...\"",
+                        path.display(),
+                        n + 1
+                    ))
+                }
+            }
+        }
+        Ok(StaticGroups { members })
+    }
+}
+
+impl GroupResolver for StaticGroups {
+    fn groups(&self, identity: &Identity) -> Result<Vec<String>, String> {
+        let mut groups = vec![];
+        for key in &[&identity.principal, &identity.name] {
+            for group in self.members.get(*key).into_iter().flatten() {
+                if !groups.contains(group) {
+                    groups.push(group.clone());
+                }
+            }
+        }
+        Ok(groups)
+    }
+}
+
+/// Groups of the local account with the forwarded name, as seen by NSS
+/// (so /etc/group, sssd, etc.). Makes sense together with `local_name`.
+#[derive(Debug)]
+pub struct NssGroups;
+
+impl GroupResolver for NssGroups {
+    fn groups(&self, identity: &Identity) -> Result<Vec<String>, String> {
+        let name = CString::new(identity.name.as_str())
+            .map_err(|_| format!("Invalid user name {:?}", identity.name))?;
+        let primary_gid = match primary_gid(&name)? {
+            Some(gid) => gid,
+            None => {
+                debug!("No local account {}", identity.name);
+                return Ok(vec![]);
+            }
+        };
+
+        let mut gids: Vec<libc::gid_t> = vec![0; 32];
+        loop {
+            let mut count = gids.len() as libc::c_int;
+            let ret = unsafe {
+                libc::getgrouplist(name.as_ptr(), primary_gid, gids.as_mut_ptr(), &mut count)
+            };
+            if ret >= 0 {
+                gids.truncate(count as usize);
+                break;
+            }
+            // Too small, and count now says how big it has to be
+            let needed = (count as usize).max(gids.len() * 2);
+            gids.resize(needed, 0);
+        }
+        let mut groups = vec![];
+        for gid in gids {
+            if let Some(name) = group_name(gid)? {
+                groups.push(name);
+            }
+        }
+        Ok(groups)
+    }
+}
+
+fn primary_gid(name: &CStr) -> Result<Option<libc::gid_t>, String> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match ret {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(pwd.pw_gid)),
+            libc::ERANGE => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            e => return Err(format!("getpwnam_r: error {}", e)),
+        }
+    }
+}
+
+fn group_name(gid: libc::gid_t) -> Result<Option<String>, String> {
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let ret =
+            unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match ret {
+            // A gid without a name isn't usable in rules anyway
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let name = unsafe { CStr::from_ptr(grp.gr_name) };
+                return Ok(Some(name.to_string_lossy().into_owned()));
+            }
+            libc::ERANGE => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            e => return Err(format!("getgrgid_r: error {}", e)),
+        }
+    }
+}
+
+/// How to find the groups of a user in an LDAP directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LdapSettings {
+    /// `ldap://` or `ldaps://` URL of the server
+    pub url: String,
+    /// Where to search for groups
+    pub base: String,
+    /// Simple bind credentials, by default the search is anonymous
+    pub bind_dn: Option<String>,
+    pub bind_password_file: Option<std::path::PathBuf>,
+    /// Search filter, `{user}` is replaced by the forwarded name and
+    /// `{principal}` by the full principal (both escaped)
+    pub filter: String,
+    /// Attribute with the group's name
+    pub group_attribute: String,
+    /// Seconds to wait for connecting and for each operation, 0 waits forever
+    pub timeout: u64,
+}
+
+impl Default for LdapSettings {
+    fn default() -> LdapSettings {
+        LdapSettings {
+            url: String::new(),
+            base: String::new(),
+            bind_dn: None,
+            bind_password_file: None,
+            filter: String::from("(&(objectClass=posixGroup)(memberUid={user}))"),
+            group_attribute: String::from("cn"),
+            timeout: 10,
+        }
+    }
+}
+
+/// Groups found by an LDAP search, with a new connection for every lookup.
+#[derive(Debug)]
+pub struct LdapGroups {
+    settings: LdapSettings,
+    bind_password: Option<String>,
+}
+
+impl LdapGroups {
+    pub fn new(settings: LdapSettings) -> Result<LdapGroups, String> {
+        let bind_password = match settings.bind_password_file {
+            Some(ref path) => Some(
+                fs::read_to_string(path)
+                    .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?
+                    .trim_end_matches('\n')
+                    .to_string(),
+            ),
+            None => None,
+        };
+        Ok(LdapGroups {
+            settings,
+            bind_password,
+        })
+    }
+}
+
+impl GroupResolver for LdapGroups {
+    fn groups(&self, identity: &Identity) -> Result<Vec<String>, String> {
+        let s = &self.settings;
+        let timeout = configuration::timeout(s.timeout);
+        let mut conn_settings = LdapConnSettings::new();
+        if let Some(timeout) = timeout {
+            conn_settings = conn_settings.set_conn_timeout(timeout);
+        }
+        let mut ldap = LdapConn::with_settings(conn_settings, &s.url)
+            .map_err(|e| format!("{}: {}", s.url, e))?;
+        // Applies to the next operation only
+        let with_timeout = |ldap: &mut LdapConn| {
+            if let Some(timeout) = timeout {
+                ldap.with_timeout(timeout);
+            }
+        };
+        if let Some(ref dn) = s.bind_dn {
+            let password = self.bind_password.as_ref().map_or("", String::as_str);
+            with_timeout(&mut ldap);
+            ldap.simple_bind(dn, password)
+                .and_then(|r| r.success())
+                .map_err(|e| format!("Cannot bind as {}: {}", dn, e))?;
+        }
+        let filter = s
+            .filter
+            .replace("{user}", &ldap3::ldap_escape(identity.name.as_str()))
+            .replace(
+                "{principal}",
+                &ldap3::ldap_escape(identity.principal.as_str()),
+            );
+        with_timeout(&mut ldap);
+        let (entries, _) = ldap
+            .search(
+                &s.base,
+                Scope::Subtree,
+                &filter,
+                vec![s.group_attribute.as_str()],
+            )
+            .and_then(|r| r.success())
+            .map_err(|e| format!("Search for {} failed: {}", filter, e))?;
+        let _ = ldap.unbind();
+        Ok(entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .filter_map(|mut e| e.attrs.remove(&s.group_attribute))
+            .flatten()
+            .collect())
+    }
+}
+
+/// Remembers the groups of each principal for a while.
+#[derive(Debug)]
+pub struct CachedGroups {
+    inner: Box<dyn GroupResolver>,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+}
+
+impl CachedGroups {
+    pub fn new(inner: Box<dyn GroupResolver>, ttl: Duration) -> CachedGroups {
+        CachedGroups {
+            inner,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl GroupResolver for CachedGroups {
+    fn groups(&self, identity: &Identity) -> Result<Vec<String>, String> {
+        if let Some((fetched, groups)) = self.cache.lock().unwrap().get(&identity.principal) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(groups.clone());
+            }
+        }
+        // Not holding the lock here, a slow lookup shouldn't block others
+        let groups = self.inner.groups(identity)?;
+        let mut cache = self.cache.lock().unwrap();
+        let ttl = self.ttl;
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < ttl);
+        cache.insert(identity.principal.clone(), (Instant::now(), groups.clone()));
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::process::{Child, Command};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn identity(principal: &str, name: &str) -> Identity {
+        Identity {
+            principal: String::from(principal),
+            name: String::from(name),
+            groups: vec![],
+            delegated: None,
+        }
+    }
+
+    fn groups_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "spnego-proxy-groups-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn static_groups_by_principal_or_name() {
+        let path = groups_file(
+            "members",
+            "# group: members\n\nadmins: alice@EXAMPLE.COM bob@EXAMPLE.COM\n ops : alice carol\n",
+        );
+        let groups = StaticGroups::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let alice = identity("alice@EXAMPLE.COM", "alice");
+        assert_eq!(groups.groups(&alice).unwrap(), vec!["admins", "ops"]);
+        let bob = identity("bob@EXAMPLE.COM", "bob@EXAMPLE.COM");
+        assert_eq!(groups.groups(&bob).unwrap(), vec!["admins"]);
+        let dave = identity("dave@EXAMPLE.COM", "dave");
+        assert!(groups.groups(&dave).unwrap().is_empty());
+    }
+
+    #[test]
+    fn static_groups_reject_bad_lines() {
+        let path = groups_file("bad", "admins: alice\nbob\n");
+        let err = StaticGroups::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            err.ends_with(":2: expected \"GROUP: MEMBER...\""),
+            "{}",
+            err
+        );
+    }
+
+    // Answers with how many lookups it's done so far
+    #[derive(Debug, Default)]
+    struct Counting(AtomicUsize);
+
+    impl GroupResolver for Counting {
+        fn groups(&self, identity: &Identity) -> Result<Vec<String>, String> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(vec![format!("{}-{}", identity.name, n)])
+        }
+    }
+
+    #[test]
+    fn cached_groups_expire() {
+        let cached = CachedGroups::new(Box::new(Counting::default()), Duration::from_millis(200));
+        let alice = identity("alice@EXAMPLE.COM", "alice");
+        let bob = identity("bob@EXAMPLE.COM", "bob");
+        assert_eq!(cached.groups(&alice).unwrap(), vec!["alice-1"]);
+        assert_eq!(cached.groups(&alice).unwrap(), vec!["alice-1"]);
+        assert_eq!(cached.groups(&bob).unwrap(), vec!["bob-2"]);
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(cached.groups(&alice).unwrap(), vec!["alice-3"]);
+    }
+
+    #[test]
+    fn ldap_lookup_times_out() {
+        // Accepts connections (through the backlog), but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ldap = LdapGroups::new(LdapSettings {
+            url: format!("ldap://{}", listener.local_addr().unwrap()),
+            base: String::from("dc=example,dc=com"),
+            timeout: 1,
+            ..LdapSettings::default()
+        })
+        .unwrap();
+        let started = Instant::now();
+        assert!(ldap
+            .groups(&identity("alice@EXAMPLE.COM", "alice"))
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    const DIRECTORY: &str = "dn: dc=test
+objectClass: dcObject
+objectClass: organization
+dc: test
+o: test
+
+dn: ou=groups,dc=test
+objectClass: organizationalUnit
+ou: groups
+
+dn: cn=admins,ou=groups,dc=test
+objectClass: posixGroup
+cn: admins
+gidNumber: 1001
+memberUid: alice
+
+dn: cn=ops,ou=groups,dc=test
+objectClass: posixGroup
+cn: ops
+gidNumber: 1002
+memberUid: alice
+memberUid: bob
+memberUid: carol@TEST
+
+";
+
+    // Throwaway slapd serving DIRECTORY, with cn=admin,dc=test / secret
+    struct Slapd {
+        url: String,
+        dir: PathBuf,
+        child: Child,
+    }
+
+    impl Slapd {
+        fn start() -> Slapd {
+            let slapd = [
+                "/usr/sbin/slapd",
+                "/usr/libexec/slapd",
+                "/usr/local/libexec/slapd",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| p.is_file())
+            .expect("OpenLDAP's slapd not found");
+            let schema = ["/etc/ldap/schema", "/etc/openldap/schema"]
+                .iter()
+                .map(PathBuf::from)
+                .find(|p| p.join("nis.schema").is_file())
+                .expect("OpenLDAP schema files not found");
+            // Distributions build the backend as a module, when it's not built in
+            let modules = ["/usr/lib/ldap", "/usr/lib64/openldap", "/usr/lib/openldap"]
+                .iter()
+                .map(PathBuf::from)
+                .find(|p| p.join("back_mdb.la").is_file() || p.join("back_mdb.so").is_file())
+                .map_or_else(String::new, |dir| {
+                    format!("modulepath {}\nmoduleload back_mdb\n", dir.display())
+                });
+
+            let dir =
+                std::env::temp_dir().join(format!("spnego-proxy-slapd-{}", std::process::id()));
+            fs::create_dir_all(dir.join("db")).unwrap();
+            let conf = dir.join("slapd.conf");
+            fs::write(
+                &conf,
+                format!(
+                    "include {schema}/core.schema
+include {schema}/cosine.schema
+include {schema}/nis.schema
+{modules}pidfile {dir}/slapd.pid
+database mdb
+maxsize 10485760
+suffix \"dc=test\"
+rootdn \"cn=admin,dc=test\"
+rootpw secret
+directory {dir}/db
+",
+                    schema = schema.display(),
+                    modules = modules,
+                    dir = dir.display(),
+                ),
+            )
+            .unwrap();
+            let ldif = dir.join("directory.ldif");
+            fs::write(&ldif, DIRECTORY).unwrap();
+            let added = Command::new(&slapd)
+                .arg("-Ta")
+                .arg("-f")
+                .arg(&conf)
+                .arg("-l")
+                .arg(&ldif)
+                .status()
+                .unwrap();
+            assert!(added.success(), "slapadd failed");
+
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let url = format!("ldap://127.0.0.1:{}", port);
+            // -d keeps it in the foreground
+            let child = Command::new(&slapd)
+                .arg("-f")
+                .arg(&conf)
+                .arg("-h")
+                .arg(format!("{}/", url))
+                .arg("-d")
+                .arg("0")
+                .spawn()
+                .unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while TcpStream::connect(("127.0.0.1", port)).is_err() {
+                assert!(Instant::now() < deadline, "slapd didn't start");
+                thread::sleep(Duration::from_millis(50));
+            }
+            Slapd { url, dir, child }
+        }
+
+        fn groups(&self, settings: LdapSettings) -> LdapGroups {
+            LdapGroups::new(LdapSettings {
+                url: self.url.clone(),
+                base: String::from("ou=groups,dc=test"),
+                ..settings
+            })
+            .unwrap()
+        }
+    }
+
+    impl Drop for Slapd {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sorted(groups: Result<Vec<String>, String>) -> Vec<String> {
+        let mut groups = groups.unwrap();
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    #[ignore = "needs OpenLDAP's slapd, run with --ignored"]
+    fn ldap_groups_from_slapd() {
+        let slapd = Slapd::start();
+        let ldap = slapd.groups(LdapSettings::default());
+        let alice = identity("alice@TEST", "alice");
+        assert_eq!(sorted(ldap.groups(&alice)), vec!["admins", "ops"]);
+        assert_eq!(
+            sorted(ldap.groups(&identity("bob@TEST", "bob"))),
+            vec!["ops"]
+        );
+        // Escaped, not a wildcard
+        assert!(ldap.groups(&identity("*@TEST", "*")).unwrap().is_empty());
+
+        let by_principal = slapd.groups(LdapSettings {
+            filter: String::from("(&(objectClass=posixGroup)(memberUid={principal}))"),
+            ..LdapSettings::default()
+        });
+        assert_eq!(
+            sorted(by_principal.groups(&identity("carol@TEST", "carol"))),
+            vec!["ops"]
+        );
+
+        let password = slapd.dir.join("password");
+        fs::write(&password, "secret\n").unwrap();
+        let bound = slapd.groups(LdapSettings {
+            bind_dn: Some(String::from("cn=admin,dc=test")),
+            bind_password_file: Some(password.clone()),
+            ..LdapSettings::default()
+        });
+        assert_eq!(sorted(bound.groups(&alice)), vec!["admins", "ops"]);
+        fs::write(&password, "wrong\n").unwrap();
+        let wrong = slapd.groups(LdapSettings {
+            bind_dn: Some(String::from("cn=admin,dc=test")),
+            bind_password_file: Some(password),
+            ..LdapSettings::default()
+        });
+        assert!(wrong.groups(&alice).is_err());
+    }
+}
@@ -1,22 +1,63 @@
-use gssapi_sys;
 use std::error;
+use std::ffi::CString;
 use std::fmt;
 use std::marker::PhantomData;
 use std::ptr;
 use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-const GSS_C_NO_CONTEXT: gssapi_sys::gss_ctx_id_t = ptr::null_mut();
-const GSS_C_NO_CREDENTIAL: gssapi_sys::gss_cred_id_t = ptr::null_mut();
-const GSS_C_NO_CHANNEL_BINDINGS: gssapi_sys::gss_channel_bindings_t = ptr::null_mut();
-const GSS_C_NO_OID: gssapi_sys::gss_OID = ptr::null_mut();
+const GSS_C_NO_CONTEXT: ffi::gss_ctx_id_t = ptr::null_mut();
+const GSS_C_NO_CREDENTIAL: ffi::gss_cred_id_t = ptr::null_mut();
+const GSS_C_NO_CHANNEL_BINDINGS: ffi::gss_channel_bindings_t = ptr::null_mut();
+const GSS_C_NO_OID: ffi::gss_OID = ptr::null_mut();
+const GSS_C_NO_OID_SET: ffi::gss_OID_set = ptr::null_mut();
+const GSS_C_INDEFINITE: u32 = 0xffff_ffff;
+// 1.3.6.1.5.5.2
+const SPNEGO_MECHANISM: &[u8] = b"\x2b\x06\x01\x05\x05\x02";
+// Routine errors the proxy reports itself, in the major status bits
+// (shifted by GSS_C_ROUTINE_ERROR_OFFSET) {
+const GSS_S_FAILURE: u32 = 13 << 16;
+const GSS_S_BAD_NAME: u32 = 2 << 16;
+const GSS_S_NO_CRED: u32 = 7 << 16;
+const GSS_S_UNAUTHORIZED: u32 = 15 << 16;
+// }
+// gss_cred_usage_t values {
+const GSS_C_INITIATE: ::std::os::raw::c_int = 1;
+const GSS_C_ACCEPT: ::std::os::raw::c_int = 2;
+// }
+// Name types, as DER encoded OIDs {
+// 1.2.840.113554.1.2.1.4
+const GSS_C_NT_HOSTBASED_SERVICE: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x01\x04";
+// 1.2.840.113554.1.2.2.1
+const GSS_KRB5_NT_PRINCIPAL_NAME: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x02\x01";
+// }
 // gss_display_status types {
 const GSS_C_GSS_CODE: ::std::os::raw::c_int = 1;
 const GSS_C_MECH_CODE: ::std::os::raw::c_int = 2;
 // }
 
+// Handles that failed to be released, see `release_failures`
+static RELEASE_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// How many GSS-API objects couldn't be released since the start. Those
+/// failures are logged and otherwise ignored, the object might leak.
+pub fn release_failures() -> usize {
+    RELEASE_FAILURES.load(Ordering::SeqCst)
+}
+
+fn release_failed(function: &str, major: u32, minor: u32) {
+    RELEASE_FAILURES.fetch_add(1, Ordering::SeqCst);
+    error!(
+        "Error in {}: {}",
+        function,
+        GSSError::new(major, minor, GSS_C_NO_OID)
+    );
+}
+
 pub struct GSSContext {
-    gss_ctx_id: gssapi_sys::gss_ctx_id_t,
+    gss_ctx_id: ffi::gss_ctx_id_t,
 }
 
 impl GSSContext {
@@ -32,17 +73,10 @@ impl Drop for GSSContext {
         if self.gss_ctx_id != GSS_C_NO_CONTEXT {
             let mut minor: u32 = 0;
             let major = unsafe {
-                gssapi_sys::gss_delete_sec_context(
-                    &mut minor,
-                    &mut self.gss_ctx_id,
-                    ptr::null_mut(),
-                )
+                ffi::gss_delete_sec_context(&mut minor, &mut self.gss_ctx_id, ptr::null_mut())
             };
-            if major != gssapi_sys::GSS_S_COMPLETE {
-                panic!(
-                    "Error while dropping GSSContext: {:}",
-                    GSSError::new(major, minor, GSS_C_NO_OID)
-                )
+            if major != ffi::GSS_S_COMPLETE {
+                release_failed("gss_delete_sec_context", major, minor);
             }
             self.gss_ctx_id = GSS_C_NO_CONTEXT;
         }
@@ -52,12 +86,12 @@ impl Drop for GSSContext {
 // Application-allocated GSS buffer.
 // They only point to borrowed data.
 pub struct AppBuffer<'a> {
-    raw: gssapi_sys::gss_buffer_desc_struct,
+    raw: ffi::gss_buffer_desc_struct,
     phantom: PhantomData<&'a [u8]>,
 }
 
 impl<'a> AppBuffer<'a> {
-    fn as_gss_buffer(&self) -> *const gssapi_sys::gss_buffer_desc_struct {
+    fn as_gss_buffer(&self) -> *const ffi::gss_buffer_desc_struct {
         &self.raw
     }
 }
@@ -65,7 +99,7 @@ impl<'a> AppBuffer<'a> {
 impl<'a> From<&'a [u8]> for AppBuffer<'a> {
     fn from(value: &'a [u8]) -> AppBuffer<'a> {
         AppBuffer {
-            raw: gssapi_sys::gss_buffer_desc_struct {
+            raw: ffi::gss_buffer_desc_struct {
                 length: value.len(),
                 value: value.as_ptr() as *mut ::std::os::raw::c_void,
             },
@@ -77,7 +111,7 @@ impl<'a> From<&'a [u8]> for AppBuffer<'a> {
 impl<'a> From<&'a Vec<u8>> for AppBuffer<'a> {
     fn from(value: &'a Vec<u8>) -> AppBuffer<'a> {
         AppBuffer {
-            raw: gssapi_sys::gss_buffer_desc_struct {
+            raw: ffi::gss_buffer_desc_struct {
                 length: value.len(),
                 value: value.as_ptr() as *mut ::std::os::raw::c_void,
             },
@@ -87,20 +121,20 @@ impl<'a> From<&'a Vec<u8>> for AppBuffer<'a> {
 }
 
 pub struct GSSBuffer {
-    desc: gssapi_sys::gss_buffer_desc_struct,
+    desc: ffi::gss_buffer_desc_struct,
 }
 
 impl GSSBuffer {
     fn new() -> GSSBuffer {
         GSSBuffer {
-            desc: gssapi_sys::gss_buffer_desc_struct {
+            desc: ffi::gss_buffer_desc_struct {
                 length: 0,
                 value: ptr::null_mut(),
             },
         }
     }
 
-    fn as_gss_buffer_mut(&mut self) -> gssapi_sys::gss_buffer_t {
+    fn as_gss_buffer_mut(&mut self) -> ffi::gss_buffer_t {
         &mut self.desc
     }
 
@@ -109,8 +143,7 @@ impl GSSBuffer {
     }
 
     pub fn as_bytes(&self) -> &[u8] {
-        if self.is_empty() {
-            assert!(self.desc.value.is_null());
+        if self.is_empty() || self.desc.value.is_null() {
             &[]
         } else {
             unsafe { slice::from_raw_parts(self.desc.value as *const u8, self.desc.length) }
@@ -122,150 +155,861 @@ impl Drop for GSSBuffer {
     fn drop(&mut self) {
         if !self.desc.value.is_null() {
             let mut minor: u32 = 0;
-            let major = unsafe { gssapi_sys::gss_release_buffer(&mut minor, &mut self.desc) };
-            if major != gssapi_sys::GSS_S_COMPLETE {
-                panic!(
-                    "Error while dropping GSSBuffer: {}",
-                    GSSError::new(major, minor, GSS_C_NO_OID)
-                )
+            let major = unsafe { ffi::gss_release_buffer(&mut minor, &mut self.desc) };
+            if major != ffi::GSS_S_COMPLETE {
+                release_failed("gss_release_buffer", major, minor);
             }
         }
     }
 }
 
 pub struct GSSName {
-    name: *mut gssapi_sys::gss_name_struct,
+    name: *mut ffi::gss_name_struct,
 }
 
 impl GSSName {
-    fn from_raw(name: *mut gssapi_sys::gss_name_struct) -> GSSName {
+    fn from_raw(name: *mut ffi::gss_name_struct) -> GSSName {
         GSSName { name }
     }
 
-    pub fn display_name(&self) -> Option<GSSBuffer> {
+    /// Import a service name, either host based (`HTTP@app.example.com`)
+    /// or a Kerberos principal (`HTTP/app.example.com[@REALM]`).
+    pub fn import_service(name: &str) -> Result<GSSName, GSSError> {
+        if name.contains('/') {
+            GSSName::import(name, GSS_KRB5_NT_PRINCIPAL_NAME)
+        } else {
+            GSSName::import(name, GSS_C_NT_HOSTBASED_SERVICE)
+        }
+    }
+
+    /// Import a Kerberos principal name (`alice@EXAMPLE.COM`).
+    pub fn import_principal(name: &str) -> Result<GSSName, GSSError> {
+        GSSName::import(name, GSS_KRB5_NT_PRINCIPAL_NAME)
+    }
+
+    fn import(name: &str, name_type: &[u8]) -> Result<GSSName, GSSError> {
+        let mut oid = ffi::gss_OID_desc_struct {
+            length: name_type.len() as u32,
+            elements: name_type.as_ptr() as *mut ::std::os::raw::c_void,
+        };
+        let buf = AppBuffer::from(name.as_bytes());
+        let mut raw_name: *mut ffi::gss_name_struct = ptr::null_mut();
+        let mut minor: u32 = 0;
+        let major = unsafe {
+            ffi::gss_import_name(
+                &mut minor,
+                buf.as_gss_buffer() as *mut ffi::gss_buffer_desc_struct,
+                &mut oid,
+                &mut raw_name,
+            )
+        };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(GSSName::from_raw(raw_name))
+        } else {
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
+        }
+    }
+
+    /// The name as text. Names that aren't valid UTF-8 are rejected as
+    /// GSS_S_BAD_NAME.
+    pub fn display_name(&self) -> Result<String, GSSError> {
         let mut buf = GSSBuffer::new();
         let mut minor: u32 = 0;
         let major = unsafe {
-            gssapi_sys::gss_display_name(
+            ffi::gss_display_name(
                 &mut minor,
                 self.name,
                 buf.as_gss_buffer_mut(),
-                &mut GSS_C_NO_OID,
+                ptr::null_mut(), // output_name_type
             )
         };
-        if major == gssapi_sys::GSS_S_COMPLETE {
-            Some(buf)
+        if major != ffi::GSS_S_COMPLETE {
+            return Err(GSSError::new(major, minor, GSS_C_NO_OID));
+        }
+        match str::from_utf8(buf.as_bytes()) {
+            Ok(name) => Ok(String::from(name)),
+            Err(_) => {
+                info!(
+                    "Name isn't valid UTF-8: {}",
+                    String::from_utf8_lossy(buf.as_bytes())
+                );
+                Err(GSSError::new(GSS_S_BAD_NAME, 0, GSS_C_NO_OID))
+            }
+        }
+    }
+
+    /// Local account name, as mapped by the mechanism (for Kerberos: the
+    /// `auth_to_local` rules from krb5.conf).
+    pub fn local_name(&self) -> Result<GSSBuffer, GSSError> {
+        let mut buf = GSSBuffer::new();
+        let mut minor: u32 = 0;
+        let major =
+            unsafe { gss_localname(&mut minor, self.name, GSS_C_NO_OID, buf.as_gss_buffer_mut()) };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(buf)
         } else {
-            None
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
         }
     }
 }
 
 impl Drop for GSSName {
     fn drop(&mut self) {
+        if !self.name.is_null() {
+            let mut minor: u32 = 0;
+            let major = unsafe { ffi::gss_release_name(&mut minor, &mut self.name) };
+            if major != ffi::GSS_S_COMPLETE {
+                release_failed("gss_release_name", major, minor);
+            }
+        }
+    }
+}
+
+pub struct GSSCredential {
+    cred_id: ffi::gss_cred_id_t,
+}
+
+impl GSSCredential {
+    /// Acquire credentials for accepting contexts. Without a name any
+    /// principal from the keytab is accepted.
+    pub fn acquire_acceptor(name: Option<&GSSName>) -> Result<GSSCredential, GSSError> {
+        let mut cred_id = GSS_C_NO_CREDENTIAL;
+        let mut minor: u32 = 0;
+        let major = unsafe {
+            ffi::gss_acquire_cred(
+                &mut minor,
+                name.map_or(ptr::null_mut(), |n| n.name),
+                GSS_C_INDEFINITE,
+                GSS_C_NO_OID_SET,
+                GSS_C_ACCEPT,
+                &mut cred_id,
+                ptr::null_mut(), // actual_mechs
+                ptr::null_mut(), // time_rec
+            )
+        };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(GSSCredential { cred_id })
+        } else {
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
+        }
+    }
+
+    /// Get a TGT for `name` with a password, into a memory ccache.
+    pub fn acquire_with_password(
+        name: &GSSName,
+        password: &str,
+    ) -> Result<GSSCredential, GSSError> {
+        let password = AppBuffer::from(password.as_bytes());
+        let mut cred_id = GSS_C_NO_CREDENTIAL;
+        let mut minor: u32 = 0;
+        let major = unsafe {
+            gss_acquire_cred_with_password(
+                &mut minor,
+                name.name,
+                password.as_gss_buffer(),
+                GSS_C_INDEFINITE,
+                GSS_C_NO_OID_SET,
+                GSS_C_INITIATE,
+                &mut cred_id,
+                ptr::null_mut(), // actual_mechs
+                ptr::null_mut(), // time_rec
+            )
+        };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(GSSCredential { cred_id })
+        } else {
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
+        }
+    }
+
+    /// Acquire credentials for initiating contexts from a ccache.
+    pub fn acquire_initiator_from_ccache(ccache: &str) -> Result<GSSCredential, GSSError> {
+        GSSCredential::acquire_from(None, GSS_C_INITIATE, &[("ccache", ccache)])
+    }
+
+    /// Acquire credentials for initiating contexts as `name`, getting
+    /// a TGT with the key from `keytab` (or the default client keytab)
+    /// into a thread-private memory ccache.
+    pub fn acquire_initiator_from_keytab(
+        name: &GSSName,
+        keytab: Option<&str>,
+    ) -> Result<GSSCredential, GSSError> {
+        let ccache = format!("MEMORY:spnego-proxy-{:?}", ::std::thread::current().id());
+        let mut store = vec![("ccache", ccache.as_str())];
+        if let Some(keytab) = keytab {
+            store.push(("client_keytab", keytab));
+        }
+        GSSCredential::acquire_from(Some(name), GSS_C_INITIATE, &store)
+    }
+
+    fn acquire_from(
+        name: Option<&GSSName>,
+        usage: ::std::os::raw::c_int,
+        store: &[(&str, &str)],
+    ) -> Result<GSSCredential, GSSError> {
+        let mut store = CredStore::new(store)?;
+        let mut cred_id = GSS_C_NO_CREDENTIAL;
+        let mut minor: u32 = 0;
+        let major = unsafe {
+            gss_acquire_cred_from(
+                &mut minor,
+                name.map_or(ptr::null_mut(), |n| n.name),
+                GSS_C_INDEFINITE,
+                GSS_C_NO_OID_SET,
+                usage,
+                &store.as_set(),
+                &mut cred_id,
+                ptr::null_mut(), // actual_mechs
+                ptr::null_mut(), // time_rec
+            )
+        };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(GSSCredential { cred_id })
+        } else {
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
+        }
+    }
+
+    /// Get credentials for `user` through S4U2Self, usable for constrained
+    /// delegation (S4U2Proxy). Needs initiator credentials of a service
+    /// allowed to do that.
+    pub fn impersonate(&self, user: &GSSName) -> Result<GSSCredential, GSSError> {
+        let mut cred_id = GSS_C_NO_CREDENTIAL;
+        let mut minor: u32 = 0;
+        let major = unsafe {
+            gss_acquire_cred_impersonate_name(
+                &mut minor,
+                self.cred_id,
+                user.name,
+                GSS_C_INDEFINITE,
+                GSS_C_NO_OID_SET,
+                GSS_C_INITIATE,
+                &mut cred_id,
+                ptr::null_mut(), // actual_mechs
+                ptr::null_mut(), // time_rec
+            )
+        };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(GSSCredential { cred_id })
+        } else {
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
+        }
+    }
+
+    /// Store the credentials (e.g. delegated by a client) in a ccache,
+    /// replacing whatever it held before.
+    pub fn store_into_ccache(&self, ccache: &str) -> Result<(), GSSError> {
+        let mut store = CredStore::new(&[("ccache", ccache)])?;
         let mut minor: u32 = 0;
-        let major = unsafe { gssapi_sys::gss_release_name(&mut minor, &mut self.name) };
-        if major != 0 {
-            panic!(
-                "Error in gss_release_name: {:}",
-                GSSError::new(major, minor, GSS_C_NO_OID)
+        let major = unsafe {
+            gss_store_cred_into(
+                &mut minor,
+                self.cred_id,
+                GSS_C_INITIATE,
+                GSS_C_NO_OID,
+                1, // overwrite_cred
+                1, // default_cred
+                &store.as_set(),
+                ptr::null_mut(), // elements_stored
+                ptr::null_mut(), // cred_usage_stored
             )
+        };
+        if major == ffi::GSS_S_COMPLETE {
+            Ok(())
+        } else {
+            Err(GSSError::new(major, minor, GSS_C_NO_OID))
+        }
+    }
+}
+
+// Owns the strings a gss_key_value_set_desc points to
+struct CredStore {
+    _strings: Vec<CString>,
+    elements: Vec<gss_key_value_element_desc>,
+}
+
+impl CredStore {
+    fn new(entries: &[(&str, &str)]) -> Result<CredStore, GSSError> {
+        let mut strings = vec![];
+        let mut elements = vec![];
+        for (key, value) in entries {
+            let key = CString::new(*key);
+            let value = CString::new(*value);
+            match (key, value) {
+                (Ok(key), Ok(value)) => {
+                    elements.push(gss_key_value_element_desc {
+                        key: key.as_ptr(),
+                        value: value.as_ptr(),
+                    });
+                    strings.push(key);
+                    strings.push(value);
+                }
+                _ => return Err(GSSError::new(GSS_S_FAILURE, 0, GSS_C_NO_OID)),
+            }
+        }
+        Ok(CredStore {
+            _strings: strings,
+            elements,
+        })
+    }
+
+    fn as_set(&mut self) -> gss_key_value_set_desc {
+        gss_key_value_set_desc {
+            count: self.elements.len() as u32,
+            elements: self.elements.as_mut_ptr(),
+        }
+    }
+}
+
+impl Drop for GSSCredential {
+    fn drop(&mut self) {
+        if self.cred_id != GSS_C_NO_CREDENTIAL {
+            let mut minor: u32 = 0;
+            let major = unsafe { ffi::gss_release_cred(&mut minor, &mut self.cred_id) };
+            if major != ffi::GSS_S_COMPLETE {
+                release_failed("gss_release_cred", major, minor);
+            }
         }
     }
 }
 
+// The GSS-API (RFC 2744) types and functions used here {
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_int, c_void};
+
+    #[repr(C)]
+    pub struct gss_ctx_id_struct {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct gss_cred_id_struct {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct gss_name_struct {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct gss_channel_bindings_struct {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct gss_OID_desc_struct {
+        pub length: u32,
+        pub elements: *mut c_void,
+    }
+    #[repr(C)]
+    pub struct gss_OID_set_desc_struct {
+        pub count: usize,
+        pub elements: *mut gss_OID_desc_struct,
+    }
+    #[repr(C)]
+    pub struct gss_buffer_desc_struct {
+        pub length: usize,
+        pub value: *mut c_void,
+    }
+
+    pub type gss_ctx_id_t = *mut gss_ctx_id_struct;
+    pub type gss_cred_id_t = *mut gss_cred_id_struct;
+    pub type gss_name_t = *mut gss_name_struct;
+    pub type gss_channel_bindings_t = *mut gss_channel_bindings_struct;
+    pub type gss_OID = *mut gss_OID_desc_struct;
+    pub type gss_OID_set = *mut gss_OID_set_desc_struct;
+    pub type gss_buffer_t = *mut gss_buffer_desc_struct;
+
+    pub const GSS_S_COMPLETE: u32 = 0;
+    pub const GSS_S_CONTINUE_NEEDED: u32 = 1;
+
+    pub const GSS_C_DELEG_FLAG: u32 = 1;
+    pub const GSS_C_MUTUAL_FLAG: u32 = 2;
+    pub const GSS_C_REPLAY_FLAG: u32 = 4;
+    pub const GSS_C_SEQUENCE_FLAG: u32 = 8;
+    pub const GSS_C_CONF_FLAG: u32 = 16;
+    pub const GSS_C_INTEG_FLAG: u32 = 32;
+    pub const GSS_C_ANON_FLAG: u32 = 64;
+
+    // Linked in build.rs
+    extern "C" {
+        pub fn gss_delete_sec_context(
+            minor_status: *mut u32,
+            context_handle: *mut gss_ctx_id_t,
+            output_token: gss_buffer_t,
+        ) -> u32;
+        pub fn gss_release_buffer(minor_status: *mut u32, buffer: gss_buffer_t) -> u32;
+        pub fn gss_release_name(minor_status: *mut u32, name: *mut gss_name_t) -> u32;
+        pub fn gss_release_cred(minor_status: *mut u32, cred_handle: *mut gss_cred_id_t) -> u32;
+        pub fn gss_import_name(
+            minor_status: *mut u32,
+            input_name_buffer: gss_buffer_t,
+            input_name_type: gss_OID,
+            output_name: *mut gss_name_t,
+        ) -> u32;
+        pub fn gss_display_name(
+            minor_status: *mut u32,
+            input_name: gss_name_t,
+            output_name_buffer: gss_buffer_t,
+            output_name_type: *mut gss_OID,
+        ) -> u32;
+        pub fn gss_display_status(
+            minor_status: *mut u32,
+            status_value: u32,
+            status_type: c_int,
+            mech_type: gss_OID,
+            message_context: *mut u32,
+            status_string: gss_buffer_t,
+        ) -> u32;
+        pub fn gss_acquire_cred(
+            minor_status: *mut u32,
+            desired_name: gss_name_t,
+            time_req: u32,
+            desired_mechs: gss_OID_set,
+            cred_usage: c_int,
+            output_cred_handle: *mut gss_cred_id_t,
+            actual_mechs: *mut gss_OID_set,
+            time_rec: *mut u32,
+        ) -> u32;
+        pub fn gss_accept_sec_context(
+            minor_status: *mut u32,
+            context_handle: *mut gss_ctx_id_t,
+            acceptor_cred_handle: gss_cred_id_t,
+            input_token_buffer: gss_buffer_t,
+            input_chan_bindings: gss_channel_bindings_t,
+            src_name: *mut gss_name_t,
+            mech_type: *mut gss_OID,
+            output_token: gss_buffer_t,
+            ret_flags: *mut u32,
+            time_rec: *mut u32,
+            delegated_cred_handle: *mut gss_cred_id_t,
+        ) -> u32;
+        pub fn gss_init_sec_context(
+            minor_status: *mut u32,
+            initiator_cred_handle: gss_cred_id_t,
+            context_handle: *mut gss_ctx_id_t,
+            target_name: gss_name_t,
+            mech_type: gss_OID,
+            req_flags: u32,
+            time_req: u32,
+            input_chan_bindings: gss_channel_bindings_t,
+            input_token: gss_buffer_t,
+            actual_mech_type: *mut gss_OID,
+            output_token: gss_buffer_t,
+            ret_flags: *mut u32,
+            time_rec: *mut u32,
+        ) -> u32;
+    }
+}
+// }
+
+// MIT extensions {
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct gss_key_value_element_desc {
+    key: *const ::std::os::raw::c_char,
+    value: *const ::std::os::raw::c_char,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct gss_key_value_set_desc {
+    count: u32,
+    elements: *mut gss_key_value_element_desc,
+}
+
+extern "C" {
+    fn krb5_gss_register_acceptor_identity(keytab: *const ::std::os::raw::c_char) -> u32;
+    fn gss_localname(
+        minor_status: *mut u32,
+        name: *mut ffi::gss_name_struct,
+        mech_type: ffi::gss_OID,
+        localname: ffi::gss_buffer_t,
+    ) -> u32;
+    fn gss_acquire_cred_with_password(
+        minor_status: *mut u32,
+        desired_name: *mut ffi::gss_name_struct,
+        password: *const ffi::gss_buffer_desc_struct,
+        time_req: u32,
+        desired_mechs: ffi::gss_OID_set,
+        cred_usage: ::std::os::raw::c_int,
+        output_cred_handle: *mut ffi::gss_cred_id_t,
+        actual_mechs: *mut ffi::gss_OID_set,
+        time_rec: *mut u32,
+    ) -> u32;
+    fn gss_acquire_cred_from(
+        minor_status: *mut u32,
+        desired_name: *mut ffi::gss_name_struct,
+        time_req: u32,
+        desired_mechs: ffi::gss_OID_set,
+        cred_usage: ::std::os::raw::c_int,
+        cred_store: *const gss_key_value_set_desc,
+        output_cred_handle: *mut ffi::gss_cred_id_t,
+        actual_mechs: *mut ffi::gss_OID_set,
+        time_rec: *mut u32,
+    ) -> u32;
+    fn gss_acquire_cred_impersonate_name(
+        minor_status: *mut u32,
+        impersonator_cred_handle: ffi::gss_cred_id_t,
+        desired_name: *mut ffi::gss_name_struct,
+        time_req: u32,
+        desired_mechs: ffi::gss_OID_set,
+        cred_usage: ::std::os::raw::c_int,
+        output_cred_handle: *mut ffi::gss_cred_id_t,
+        actual_mechs: *mut ffi::gss_OID_set,
+        time_rec: *mut u32,
+    ) -> u32;
+    fn gss_store_cred_into(
+        minor_status: *mut u32,
+        input_cred_handle: ffi::gss_cred_id_t,
+        input_usage: ::std::os::raw::c_int,
+        desired_mech: ffi::gss_OID,
+        overwrite_cred: u32,
+        default_cred: u32,
+        cred_store: *const gss_key_value_set_desc,
+        elements_stored: *mut ffi::gss_OID_set,
+        cred_usage_stored: *mut ::std::os::raw::c_int,
+    ) -> u32;
+}
+// }
+
+/// Use `keytab` instead of the default (`KRB5_KTNAME`) one for all
+/// acceptor credentials in this process.
+pub fn register_acceptor_keytab(keytab: &str) -> Result<(), String> {
+    let path = CString::new(keytab).map_err(|_| format!("Invalid keytab path {:?}", keytab))?;
+    let major = unsafe { krb5_gss_register_acceptor_identity(path.as_ptr()) };
+    if major == ffi::GSS_S_COMPLETE {
+        Ok(())
+    } else {
+        Err(format!("Cannot register keytab {:?}", keytab))
+    }
+}
+
 #[derive(Debug)]
 pub struct GSSError {
     major: u32,
-    minor: u32,
     errors: Vec<String>,
 }
 
 impl GSSError {
-    fn new(major: u32, minor: u32, mech_type: gssapi_sys::gss_OID) -> GSSError {
-        let mut errors = display_major_status(major).unwrap();
-        errors.append(&mut display_minor_status(minor, mech_type).unwrap());
+    // Falls back to the numeric codes for statuses GSS-API can't describe
+    fn new(major: u32, minor: u32, mech_type: ffi::gss_OID) -> GSSError {
+        let mut errors = display_major_status(major)
+            .unwrap_or_else(|| vec![format!("Major status {:#010x}", major)]);
+        if minor != 0 {
+            errors.append(
+                &mut display_minor_status(minor, mech_type)
+                    .unwrap_or_else(|| vec![format!("Minor status {}", minor)]),
+            );
+        }
+        GSSError { major, errors }
+    }
+
+    /// The context got established, but lacks the `missing` flags.
+    pub fn missing_flags(missing: ContextFlags) -> GSSError {
         GSSError {
-            major,
-            minor,
-            errors,
+            major: GSS_S_UNAUTHORIZED,
+            errors: vec![format!("Context lacks required flags: {}", missing)],
+        }
+    }
+
+    /// The client didn't delegate credentials that are needed.
+    pub fn no_delegated_credentials() -> GSSError {
+        GSSError {
+            major: GSS_S_NO_CRED,
+            errors: vec![String::from("No delegated credentials")],
+        }
+    }
+
+    /// Name of the major status, without the GSS_S_ prefix: the routine
+    /// error if there's one, otherwise the calling error or the first
+    /// supplementary bit.
+    pub fn major_name(&self) -> &'static str {
+        const ROUTINE_ERRORS: &[&str] = &[
+            "COMPLETE",
+            "BAD_MECH",
+            "BAD_NAME",
+            "BAD_NAMETYPE",
+            "BAD_BINDINGS",
+            "BAD_STATUS",
+            "BAD_SIG",
+            "NO_CRED",
+            "NO_CONTEXT",
+            "DEFECTIVE_TOKEN",
+            "DEFECTIVE_CREDENTIAL",
+            "CREDENTIALS_EXPIRED",
+            "CONTEXT_EXPIRED",
+            "FAILURE",
+            "BAD_QOP",
+            "UNAUTHORIZED",
+            "UNAVAILABLE",
+            "DUPLICATE_ELEMENT",
+            "NAME_NOT_MN",
+        ];
+        const SUPPLEMENTARY: &[&str] = &[
+            "CONTINUE_NEEDED",
+            "DUPLICATE_TOKEN",
+            "OLD_TOKEN",
+            "UNSEQ_TOKEN",
+            "GAP_TOKEN",
+        ];
+        let routine = ((self.major >> 16) & 0xff) as usize;
+        let calling = (self.major >> 24) & 0xff;
+        let supplementary = self.major & 0xffff;
+        if routine != 0 {
+            ROUTINE_ERRORS.get(routine).cloned().unwrap_or("UNKNOWN")
+        } else if calling != 0 {
+            "CALL_ERROR"
+        } else if supplementary != 0 {
+            let bit = supplementary.trailing_zeros() as usize;
+            SUPPLEMENTARY.get(bit).cloned().unwrap_or("UNKNOWN")
+        } else {
+            "COMPLETE"
         }
     }
 }
 
 impl fmt::Display for GSSError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.errors.iter().map(|e| f.write_str(e)).collect()
+        for (i, e) in self.errors.iter().enumerate() {
+            if i > 0 {
+                f.write_str(": ")?;
+            }
+            f.write_str(e)?;
+        }
+        Ok(())
     }
 }
 
 impl error::Error for GSSError {
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
 
+/// Services of an established context, as `GSS_C_*_FLAG` bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ContextFlags(pub u32);
+
+impl ContextFlags {
+    pub const DELEGATION: ContextFlags = ContextFlags(ffi::GSS_C_DELEG_FLAG);
+    pub const MUTUAL: ContextFlags = ContextFlags(ffi::GSS_C_MUTUAL_FLAG);
+    pub const REPLAY: ContextFlags = ContextFlags(ffi::GSS_C_REPLAY_FLAG);
+    pub const SEQUENCE: ContextFlags = ContextFlags(ffi::GSS_C_SEQUENCE_FLAG);
+    pub const CONFIDENTIALITY: ContextFlags = ContextFlags(ffi::GSS_C_CONF_FLAG);
+    pub const INTEGRITY: ContextFlags = ContextFlags(ffi::GSS_C_INTEG_FLAG);
+    pub const ANONYMOUS: ContextFlags = ContextFlags(ffi::GSS_C_ANON_FLAG);
+
+    pub fn union(self, other: ContextFlags) -> ContextFlags {
+        ContextFlags(self.0 | other.0)
+    }
+
+    /// Flags of `required` that aren't set here.
+    pub fn missing(self, required: ContextFlags) -> ContextFlags {
+        ContextFlags(required.0 & !self.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for ContextFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: &[(ContextFlags, &str)] = &[
+            (ContextFlags::DELEGATION, "delegation"),
+            (ContextFlags::MUTUAL, "mutual"),
+            (ContextFlags::REPLAY, "replay"),
+            (ContextFlags::SEQUENCE, "sequence"),
+            (ContextFlags::CONFIDENTIALITY, "confidentiality"),
+            (ContextFlags::INTEGRITY, "integrity"),
+            (ContextFlags::ANONYMOUS, "anonymous"),
+        ];
+        let names: Vec<&str> = NAMES
+            .iter()
+            .filter(|(flag, _)| self.0 & flag.0 != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(", "))
+        }
+    }
+}
+
 pub enum AcceptResult {
     ContinueNeeded(GSSBuffer),
-    Complete(GSSBuffer, GSSName),
+    Complete {
+        /// Last output token, for mutual authentication
+        token: GSSBuffer,
+        client: GSSName,
+        /// Credentials delegated by the client
+        delegated: Option<GSSCredential>,
+        flags: ContextFlags,
+        /// How long the context stays valid, None if indefinitely
+        lifetime: Option<Duration>,
+    },
 }
 
 pub fn accept_sec_context(
     ctx: &mut GSSContext,
+    cred: Option<&GSSCredential>,
     received_token: &AppBuffer,
 ) -> Result<AcceptResult, GSSError> {
     let mut minor: u32 = 0;
     let mut output_token = GSSBuffer::new();
-    let mut client_name: *mut gssapi_sys::gss_name_struct = ptr::null_mut();
-    let mut mech_type: gssapi_sys::gss_OID = ptr::null_mut();
+    let mut client_name: *mut ffi::gss_name_struct = ptr::null_mut();
+    let mut mech_type: ffi::gss_OID = ptr::null_mut();
+    let mut delegated_cred = GSS_C_NO_CREDENTIAL;
+    let mut ret_flags: u32 = 0;
+    let mut time_rec: u32 = 0;
     let major = unsafe {
-        gssapi_sys::gss_accept_sec_context(
+        ffi::gss_accept_sec_context(
             &mut minor,
             &mut ctx.gss_ctx_id,
-            GSS_C_NO_CREDENTIAL,
-            received_token.as_gss_buffer() as *mut gssapi_sys::gss_buffer_desc_struct,
+            cred.map_or(GSS_C_NO_CREDENTIAL, |c| c.cred_id),
+            received_token.as_gss_buffer() as *mut ffi::gss_buffer_desc_struct,
             GSS_C_NO_CHANNEL_BINDINGS,
             &mut client_name,
             &mut mech_type,
             output_token.as_gss_buffer_mut(),
+            &mut ret_flags,
+            &mut time_rec,
+            &mut delegated_cred,
+        )
+    };
+    match major {
+        ffi::GSS_S_CONTINUE_NEEDED => Ok(AcceptResult::ContinueNeeded(output_token)),
+        ffi::GSS_S_COMPLETE => Ok(AcceptResult::Complete {
+            token: output_token,
+            client: GSSName::from_raw(client_name),
+            delegated: if delegated_cred == GSS_C_NO_CREDENTIAL {
+                None
+            } else {
+                Some(GSSCredential {
+                    cred_id: delegated_cred,
+                })
+            },
+            flags: ContextFlags(ret_flags),
+            lifetime: if time_rec == GSS_C_INDEFINITE {
+                None
+            } else {
+                Some(Duration::from_secs(u64::from(time_rec)))
+            },
+        }),
+        _ => Err(GSSError::new(major, minor, mech_type)),
+    }
+}
+
+pub enum InitResult {
+    ContinueNeeded(GSSBuffer),
+    Complete(GSSBuffer),
+}
+
+/// Initiate (or continue) a SPNEGO context with `target`.
+pub fn init_sec_context(
+    ctx: &mut GSSContext,
+    cred: Option<&GSSCredential>,
+    target: &GSSName,
+    req_flags: ContextFlags,
+    received_token: Option<&AppBuffer>,
+) -> Result<InitResult, GSSError> {
+    let mut minor: u32 = 0;
+    let mut output_token = GSSBuffer::new();
+    let mut mech = ffi::gss_OID_desc_struct {
+        length: SPNEGO_MECHANISM.len() as u32,
+        elements: SPNEGO_MECHANISM.as_ptr() as *mut ::std::os::raw::c_void,
+    };
+    let major = unsafe {
+        ffi::gss_init_sec_context(
+            &mut minor,
+            cred.map_or(GSS_C_NO_CREDENTIAL, |c| c.cred_id),
+            &mut ctx.gss_ctx_id,
+            target.name,
+            &mut mech,
+            req_flags.0,
+            0, // time_req
+            GSS_C_NO_CHANNEL_BINDINGS,
+            received_token.map_or(ptr::null_mut(), |t| {
+                t.as_gss_buffer() as *mut ffi::gss_buffer_desc_struct
+            }),
+            ptr::null_mut(), // actual_mech_type
+            output_token.as_gss_buffer_mut(),
             ptr::null_mut(), // ret_flags
             ptr::null_mut(), // time_rec
-            ptr::null_mut(), // delegated_cred_handle
         )
     };
     match major {
-        gssapi_sys::GSS_S_CONTINUE_NEEDED => Ok(AcceptResult::ContinueNeeded(output_token)),
-        gssapi_sys::GSS_S_COMPLETE => Ok(AcceptResult::Complete(
-            output_token,
-            GSSName::from_raw(client_name),
-        )),
-        _ => Err(GSSError::new(major, minor, mech_type)),
+        ffi::GSS_S_CONTINUE_NEEDED => Ok(InitResult::ContinueNeeded(output_token)),
+        ffi::GSS_S_COMPLETE => Ok(InitResult::Complete(output_token)),
+        _ => Err(GSSError::new(major, minor, &mut mech)),
     }
 }
 
-fn display_major_status(status_code: u32) -> Result<Vec<String>, GSSError> {
+/// Check a user's password: get a TGT with it, then a service ticket for
+/// `service` and accept that with our own keys. Getting the TGT alone
+/// isn't enough, as anyone able to spoof the KDC could hand one out.
+/// Returns the authenticated name, and how long its ticket is valid.
+pub fn verify_password(
+    user: &GSSName,
+    password: &str,
+    service: &GSSName,
+    acceptor: Option<&GSSCredential>,
+) -> Result<(GSSName, Option<Duration>), GSSError> {
+    let user_cred = GSSCredential::acquire_with_password(user, password)?;
+    let mut init_ctx = GSSContext::new();
+    let mut accept_ctx = GSSContext::new();
+    let mut input: Option<Vec<u8>> = None;
+    // Kerberos needs a single round trip, SPNEGO at most two
+    for _ in 0..4 {
+        let token = {
+            let input = input.as_ref().map(AppBuffer::from);
+            match init_sec_context(
+                &mut init_ctx,
+                Some(&user_cred),
+                service,
+                ContextFlags::default(),
+                input.as_ref(),
+            )? {
+                InitResult::ContinueNeeded(buf) | InitResult::Complete(buf) => {
+                    Vec::from(buf.as_bytes())
+                }
+            }
+        };
+        if token.is_empty() {
+            break;
+        }
+        match accept_sec_context(&mut accept_ctx, acceptor, &AppBuffer::from(&token))? {
+            AcceptResult::Complete {
+                client, lifetime, ..
+            } => return Ok((client, lifetime)),
+            AcceptResult::ContinueNeeded(buf) => input = Some(Vec::from(buf.as_bytes())),
+        }
+    }
+    Err(GSSError::new(GSS_S_FAILURE, 0, GSS_C_NO_OID))
+}
+
+fn display_major_status(status_code: u32) -> Option<Vec<String>> {
     gss_display_status(status_code, GSS_C_GSS_CODE, GSS_C_NO_OID)
 }
 
-fn display_minor_status(
-    status_code: u32,
-    mech_type: gssapi_sys::gss_OID,
-) -> Result<Vec<String>, GSSError> {
+fn display_minor_status(status_code: u32, mech_type: ffi::gss_OID) -> Option<Vec<String>> {
     gss_display_status(status_code, GSS_C_MECH_CODE, mech_type)
 }
 
+// None if GSS-API can't describe the status. That's not turned into
+// a GSSError, as describing that one could fail the same way.
 fn gss_display_status(
     status_code: u32,
     status_type: ::std::os::raw::c_int,
-    mach_type: gssapi_sys::gss_OID,
-) -> Result<Vec<String>, GSSError> {
+    mach_type: ffi::gss_OID,
+) -> Option<Vec<String>> {
     let mut message_context: u32 = 0;
     let mut result = vec![];
     loop {
         let mut buf = GSSBuffer::new();
         let mut minor: u32 = 0;
         let major = unsafe {
-            gssapi_sys::gss_display_status(
+            ffi::gss_display_status(
                 &mut minor,
                 status_code,
                 status_type,
@@ -274,14 +1018,64 @@ fn gss_display_status(
                 buf.as_gss_buffer_mut(),
             )
         };
-        if major != gssapi_sys::GSS_S_COMPLETE {
-            return Err(GSSError::new(major, minor, GSS_C_NO_OID));
+        if major != ffi::GSS_S_COMPLETE {
+            debug!(
+                "Cannot display GSS status {:#x}: major {:#x}, minor {}",
+                status_code, major, minor
+            );
+            return None;
         }
-        result.push(String::from(str::from_utf8(buf.as_bytes()).unwrap()));
+        result.push(String::from_utf8_lossy(buf.as_bytes()).into_owned());
         if message_context == 0 {
             break;
         }
     }
 
-    Ok(result)
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn major_name(major: u32) -> &'static str {
+        GSSError {
+            major,
+            errors: vec![],
+        }
+        .major_name()
+    }
+
+    #[test]
+    fn major_names() {
+        assert_eq!(major_name(0), "COMPLETE");
+        // Routine errors
+        assert_eq!(major_name(GSS_S_FAILURE), "FAILURE");
+        assert_eq!(major_name(GSS_S_BAD_NAME), "BAD_NAME");
+        assert_eq!(major_name(GSS_S_NO_CRED), "NO_CRED");
+        assert_eq!(major_name(9 << 16), "DEFECTIVE_TOKEN");
+        assert_eq!(major_name(18 << 16), "NAME_NOT_MN");
+        assert_eq!(major_name(19 << 16), "UNKNOWN");
+        // Calling errors
+        assert_eq!(major_name(1 << 24), "CALL_ERROR");
+        assert_eq!(major_name(3 << 24), "CALL_ERROR");
+        // Supplementary bits, the lowest one wins
+        assert_eq!(major_name(1), "CONTINUE_NEEDED");
+        assert_eq!(major_name(1 << 2), "OLD_TOKEN");
+        assert_eq!(major_name(1 << 4), "GAP_TOKEN");
+        assert_eq!(major_name((1 << 3) | (1 << 1)), "DUPLICATE_TOKEN");
+        assert_eq!(major_name(1 << 10), "UNKNOWN");
+    }
+
+    #[test]
+    fn routine_error_wins() {
+        assert_eq!(major_name((1 << 24) | GSS_S_UNAUTHORIZED), "UNAUTHORIZED");
+        assert_eq!(major_name((12 << 16) | (1 << 2)), "CONTEXT_EXPIRED");
+        assert_eq!(major_name((2 << 24) | (1 << 3)), "CALL_ERROR");
+        assert_eq!(
+            GSSError::missing_flags(ContextFlags::MUTUAL).major_name(),
+            "UNAUTHORIZED"
+        );
+        assert_eq!(GSSError::no_delegated_credentials().major_name(), "NO_CRED");
+    }
 }
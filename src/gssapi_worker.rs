@@ -1,39 +1,99 @@
+use super::delegation;
+use super::delegation::Ccache;
 use super::gssapi;
 use super::gssapi::GSSError;
-use futures::sink::Sink;
+use super::identity::{Identity, Rewrite};
 use futures::stream::Stream;
 use futures::sync::mpsc;
-use futures::sync::mpsc::{Receiver, Sender};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures::sync::oneshot;
 use futures::Future;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+type ContextId = usize;
 
 #[derive(Debug)]
-pub enum Cmd {
-    Accept(Vec<u8>),
+enum Cmd {
+    Accept(ContextId, Vec<u8>, oneshot::Sender<Msg>),
+    Release(ContextId),
+    Initiate(
+        String,
+        Option<Arc<Ccache>>,
+        oneshot::Sender<Result<Vec<u8>, GSSError>>,
+    ),
+    VerifyPassword(String, String, oneshot::Sender<Msg>),
 }
 
 #[derive(Debug)]
 pub enum Msg {
     ContinueNeeded(Vec<u8>),
-    Accepted(Vec<u8>, String),
+    Accepted(Vec<u8>, Identity, Option<Duration>),
     Failed(GSSError),
 }
 
 #[derive(Debug)]
 pub enum AcceptResult {
     ContinueNeeded(Vec<u8>),
-    Accepted(Vec<u8>, String),
+    /// Output token, the client's identity and how long its ticket is
+    /// valid (None if it doesn't expire)
+    Accepted(Vec<u8>, Identity, Option<Duration>),
+    Failed(GSSError),
+    /// The worker thread has too many tokens queued up
+    Overloaded,
+}
+
+#[derive(Debug)]
+pub enum InitiateResult {
+    /// Token for the backend's Authorization header
+    Token(Vec<u8>),
     Failed(GSSError),
+    /// The worker thread has too many commands queued up
+    Overloaded,
 }
 
 impl Msg {
-    fn from(r: Result<gssapi::AcceptResult, gssapi::GSSError>) -> Msg {
+    /// Result of accepting a token on context `context_id`, with the
+    /// client's identity checked and built according to `settings`.
+    pub fn from(
+        r: Result<gssapi::AcceptResult, gssapi::GSSError>,
+        context_id: ContextId,
+        settings: &WorkerSettings,
+    ) -> Msg {
         match r {
-            Ok(gssapi::AcceptResult::Complete(buf, name)) => {
-                let str_name =
-                    String::from(str::from_utf8(name.display_name().unwrap().as_bytes()).unwrap());
-                Msg::Accepted(Vec::from(buf.as_bytes()), str_name.clone())
+            Ok(gssapi::AcceptResult::Complete {
+                token,
+                client,
+                delegated,
+                flags,
+                lifetime,
+            }) => {
+                let mut identity = match identity(&client, settings) {
+                    Ok(identity) => identity,
+                    Err(e) => return Msg::Failed(e),
+                };
+                debug!(
+                    "Context of {} has flags {}, valid for {:?}",
+                    identity.principal, flags, lifetime
+                );
+                let missing = flags.missing(settings.required_flags);
+                if !missing.is_empty() {
+                    info!(
+                        "Rejecting context of {} without required flags {}",
+                        identity.principal, missing
+                    );
+                    return Msg::Failed(GSSError::missing_flags(missing));
+                }
+                if let (Some(cred), Some(dir)) = (delegated, &settings.delegation_dir) {
+                    identity.delegated =
+                        store_delegated(&cred, dir, &identity.principal, context_id).map(Arc::new);
+                }
+                Msg::Accepted(Vec::from(token.as_bytes()), identity, lifetime)
             }
             Ok(gssapi::AcceptResult::ContinueNeeded(buf)) => {
                 Msg::ContinueNeeded(Vec::from(buf.as_bytes()))
@@ -43,55 +103,534 @@ impl Msg {
     }
 }
 
+fn identity(name: &gssapi::GSSName, settings: &WorkerSettings) -> Result<Identity, GSSError> {
+    let principal = name.display_name()?;
+    let local_name = if settings.local_name {
+        local_name(name, &principal)
+    } else {
+        None
+    };
+    Ok(Identity::new(
+        principal,
+        local_name,
+        &settings.name_rewrites,
+    ))
+}
+
+// Principals without an auth_to_local mapping keep their full name
+fn local_name(name: &gssapi::GSSName, principal: &str) -> Option<String> {
+    match name.local_name() {
+        Ok(buf) => str::from_utf8(buf.as_bytes()).ok().map(String::from),
+        Err(e) => {
+            debug!("No local name for {}: {}", principal, e);
+            None
+        }
+    }
+}
+
+impl From<Msg> for AcceptResult {
+    fn from(msg: Msg) -> AcceptResult {
+        match msg {
+            Msg::Accepted(v, s, l) => AcceptResult::Accepted(v, s, l),
+            Msg::ContinueNeeded(v) => AcceptResult::ContinueNeeded(v),
+            Msg::Failed(e) => AcceptResult::Failed(e),
+        }
+    }
+}
+
+fn store_delegated(
+    cred: &gssapi::GSSCredential,
+    dir: &Path,
+    principal: &str,
+    context_id: ContextId,
+) -> Option<Ccache> {
+    let path = delegation::ccache_path(dir, principal, context_id);
+    match cred.store_into_ccache(&delegation::ccache_name(&path)) {
+        Ok(()) => {
+            debug!(
+                "Stored delegated credentials of {} in {:?}",
+                principal, path
+            );
+            Some(Ccache::new(path))
+        }
+        Err(e) => {
+            warn!("Cannot store delegated credentials of {}: {}", principal, e);
+            None
+        }
+    }
+}
+
+/// Which credentials, if any, the proxy uses to authenticate to the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendAuth {
+    None,
+    Delegated,
+    Impersonate,
+}
+
+impl FromStr for BackendAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BackendAuth, String> {
+        match s {
+            "none" => Ok(BackendAuth::None),
+            "delegated" => Ok(BackendAuth::Delegated),
+            "impersonate" => Ok(BackendAuth::Impersonate),
+            other => Err(format!(
+                "Invalid backend authentication {:?}, expected none, delegated or impersonate",
+                other
+            )),
+        }
+    }
+}
+
+/// How the worker threads accept and initiate contexts.
+#[derive(Debug, Clone)]
+pub struct WorkerSettings {
+    /// Restricts which keytab entry is used, by default any of them is
+    pub service_principal: Option<String>,
+    /// Where to store credentials delegated by clients, if at all
+    pub delegation_dir: Option<PathBuf>,
+    /// Map principals to local names with the mechanism's rules
+    pub local_name: bool,
+    /// Rewrites applied to the (local) name forwarded to the backend
+    pub name_rewrites: Vec<Rewrite>,
+    /// Keytab used to get our own initiator credentials for impersonation
+    pub keytab: Option<PathBuf>,
+    /// Credentials used to authenticate to the backend
+    pub backend_auth: BackendAuth,
+    pub backend_principal: Option<String>,
+    /// Contexts without all of these flags are rejected
+    pub required_flags: gssapi::ContextFlags,
+}
+
+/// Occupancy of the worker pool, at some point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    /// Threads that haven't stopped (or panicked)
+    pub live_threads: usize,
+    pub contexts: usize,
+    pub queued: usize,
+}
+
 #[derive(Debug)]
-pub struct GSSWorker {
-    cmd_channel: Sender<(Cmd, oneshot::Sender<Msg>)>,
+struct WorkerThread {
+    cmd_channel: UnboundedSender<Cmd>,
+    // Contexts pinned to this thread
+    contexts: AtomicUsize,
+    // Accept commands sent, but not picked up yet
+    queued: AtomicUsize,
+    alive: AtomicBool,
 }
 
-impl GSSWorker {
-    pub fn new() -> GSSWorker {
-        let (cmd_tx, cmd_rx) = mpsc::channel(0);
-        ::std::thread::spawn(move || worker_thread(cmd_rx));
-        GSSWorker {
-            cmd_channel: cmd_tx,
+// Marks the thread as dead when it stops, even by panicking
+struct AliveGuard<'a>(&'a WorkerThread);
+
+impl<'a> Drop for AliveGuard<'a> {
+    fn drop(&mut self) {
+        self.0.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A fixed set of threads running GSS-API calls.
+///
+/// GSS-API contexts can't be moved between threads, so each handshake
+/// gets pinned to one of the threads for its whole lifetime.
+#[derive(Debug)]
+pub struct GSSWorkerPool {
+    threads: Vec<Arc<WorkerThread>>,
+    max_contexts: usize,
+    max_queued: usize,
+    contexts: Arc<AtomicUsize>,
+    next_id: AtomicUsize,
+}
+
+impl GSSWorkerPool {
+    /// Spawn `threads` worker threads.
+    pub fn new(
+        threads: usize,
+        max_contexts: usize,
+        max_queued: usize,
+        settings: WorkerSettings,
+    ) -> GSSWorkerPool {
+        let threads = (0..threads)
+            .map(|i| {
+                let (cmd_tx, cmd_rx) = mpsc::unbounded();
+                let thread = Arc::new(WorkerThread {
+                    cmd_channel: cmd_tx,
+                    contexts: AtomicUsize::new(0),
+                    queued: AtomicUsize::new(0),
+                    alive: AtomicBool::new(true),
+                });
+                let thread_state = thread.clone();
+                let settings = settings.clone();
+                ::std::thread::Builder::new()
+                    .name(format!("gss-worker-{}", i))
+                    .spawn(move || worker_thread(cmd_rx, &thread_state, settings))
+                    .unwrap();
+                thread
+            })
+            .collect();
+        GSSWorkerPool {
+            threads,
+            max_contexts,
+            max_queued,
+            contexts: Arc::new(AtomicUsize::new(0)),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserve a context on the least busy thread, or `None` if there are
+    /// already too many handshakes in progress.
+    pub fn checkout(&self) -> Option<GSSWorker> {
+        let previous = self.contexts.fetch_add(1, Ordering::SeqCst);
+        if previous >= self.max_contexts {
+            self.contexts.fetch_sub(1, Ordering::SeqCst);
+            warn!("GSS worker pool saturated ({} contexts)", previous);
+            return None;
+        }
+        let thread = match self.least_busy(|t| &t.contexts) {
+            Some(thread) => thread,
+            None => {
+                self.contexts.fetch_sub(1, Ordering::SeqCst);
+                error!("All GSS worker threads died");
+                return None;
+            }
+        };
+        thread.contexts.fetch_add(1, Ordering::SeqCst);
+        let worker = GSSWorker {
+            thread: thread.clone(),
+            pool_contexts: self.contexts.clone(),
+            context_id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            max_queued: self.max_queued,
+        };
+        debug!("GSS worker pool: {:?}", self.stats());
+        Some(worker)
+    }
+
+    /// Check a user's password (from Basic authentication), on the thread
+    /// with the shortest queue.
+    pub fn verify_password(
+        &self,
+        user: &str,
+        password: &str,
+    ) -> Box<dyn Future<Item = AcceptResult, Error = String> + Send> {
+        let thread = match self.least_busy(|t| &t.queued) {
+            Some(thread) => thread,
+            None => return Box::new(futures::done(Err(String::from("All worker threads died")))),
+        };
+        if thread.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            thread.queued.fetch_sub(1, Ordering::SeqCst);
+            return Box::new(futures::done(Ok(AcceptResult::Overloaded)));
         }
+        let (msg_tx, msg_rx) = oneshot::channel();
+        let sent = thread.cmd_channel.unbounded_send(Cmd::VerifyPassword(
+            String::from(user),
+            String::from(password),
+            msg_tx,
+        ));
+        if sent.is_err() {
+            thread.queued.fetch_sub(1, Ordering::SeqCst);
+            return Box::new(futures::done(Err(String::from("Worker thread died"))));
+        }
+        Box::new(
+            msg_rx
+                .map(AcceptResult::from)
+                .map_err(|_e| String::from("Worker thread died")),
+        )
     }
 
+    /// Get a token authenticating `user` to the backend, on the thread
+    /// with the shortest queue.
+    pub fn initiate(
+        &self,
+        user: &Identity,
+    ) -> Box<dyn Future<Item = InitiateResult, Error = String> + Send> {
+        let thread = match self.least_busy(|t| &t.queued) {
+            Some(thread) => thread,
+            None => return Box::new(futures::done(Err(String::from("All worker threads died")))),
+        };
+        if thread.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            thread.queued.fetch_sub(1, Ordering::SeqCst);
+            return Box::new(futures::done(Ok(InitiateResult::Overloaded)));
+        }
+        let (msg_tx, msg_rx) = oneshot::channel();
+        let sent = thread.cmd_channel.unbounded_send(Cmd::Initiate(
+            user.principal.clone(),
+            user.delegated.clone(),
+            msg_tx,
+        ));
+        if sent.is_err() {
+            thread.queued.fetch_sub(1, Ordering::SeqCst);
+            return Box::new(futures::done(Err(String::from("Worker thread died"))));
+        }
+        Box::new(
+            msg_rx
+                .map(|r| match r {
+                    Ok(token) => InitiateResult::Token(token),
+                    Err(e) => InitiateResult::Failed(e),
+                })
+                .map_err(|_e| String::from("Worker thread died")),
+        )
+    }
+
+    // Live thread with the lowest `load`; dead ones would never answer
+    fn least_busy<F>(&self, load: F) -> Option<&Arc<WorkerThread>>
+    where
+        F: Fn(&WorkerThread) -> &AtomicUsize,
+    {
+        self.threads
+            .iter()
+            .filter(|t| t.alive.load(Ordering::SeqCst))
+            .min_by_key(|t| load(t).load(Ordering::SeqCst))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            live_threads: self
+                .threads
+                .iter()
+                .filter(|t| t.alive.load(Ordering::SeqCst))
+                .count(),
+            contexts: self.contexts.load(Ordering::SeqCst),
+            queued: self
+                .threads
+                .iter()
+                .map(|t| t.queued.load(Ordering::SeqCst))
+                .sum(),
+        }
+    }
+}
+
+/// A single GSS context, living on one of the pool's threads.
+/// Dropping it releases the context.
+#[derive(Debug)]
+pub struct GSSWorker {
+    thread: Arc<WorkerThread>,
+    pool_contexts: Arc<AtomicUsize>,
+    context_id: ContextId,
+    max_queued: usize,
+}
+
+impl GSSWorker {
     pub fn accept_sec_context(
         &self,
         input_token: &[u8],
     ) -> Box<dyn Future<Item = AcceptResult, Error = String> + Send> {
+        if self.thread.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            self.thread.queued.fetch_sub(1, Ordering::SeqCst);
+            return Box::new(futures::done(Ok(AcceptResult::Overloaded)));
+        }
         let (msg_tx, msg_rx) = oneshot::channel();
+        let sent = self.thread.cmd_channel.unbounded_send(Cmd::Accept(
+            self.context_id,
+            Vec::from(input_token),
+            msg_tx,
+        ));
+        if sent.is_err() {
+            self.thread.queued.fetch_sub(1, Ordering::SeqCst);
+            return Box::new(futures::done(Err(String::from("Worker thread died"))));
+        }
         Box::new(
-            self.cmd_channel
-                .clone()
-                .send((Cmd::Accept(Vec::from(input_token)), msg_tx))
-                .map_err(|_e| String::from("Worker thread died"))
-                .and_then(|_| {
-                    msg_rx
-                        .map(|r| match r {
-                            Msg::Accepted(v, s) => AcceptResult::Accepted(v, s),
-                            Msg::ContinueNeeded(v) => AcceptResult::ContinueNeeded(v),
-                            Msg::Failed(e) => AcceptResult::Failed(e),
-                        })
-                        .map_err(|_e| String::from("Worker thread died"))
-                }),
+            msg_rx
+                .map(AcceptResult::from)
+                .map_err(|_e| String::from("Worker thread died")),
         )
     }
 }
 
-fn worker_thread(inbox: Receiver<(Cmd, oneshot::Sender<Msg>)>) {
-    let mut context = gssapi::GSSContext::new();
-    let mut inbox_iter = inbox.wait().into_iter();
+impl Drop for GSSWorker {
+    fn drop(&mut self) {
+        let _ = self
+            .thread
+            .cmd_channel
+            .unbounded_send(Cmd::Release(self.context_id));
+        self.thread.contexts.fetch_sub(1, Ordering::SeqCst);
+        self.pool_contexts.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-    while let Some(Ok((cmd, output))) = inbox_iter.next() {
-        let response = match cmd {
-            Cmd::Accept(bytes) => Msg::from(gssapi::accept_sec_context(
-                &mut context,
-                &gssapi::AppBuffer::from(&bytes),
-            )),
-        };
-        output.send(response).unwrap();
+fn worker_thread(
+    inbox: UnboundedReceiver<Cmd>,
+    thread_state: &WorkerThread,
+    settings: WorkerSettings,
+) {
+    let _alive = AliveGuard(thread_state);
+    let mut contexts: HashMap<ContextId, gssapi::GSSContext> = HashMap::new();
+    let mut credential = None;
+    let mut initiator = Initiator::default();
+    let mut inbox_iter = inbox.wait();
+
+    while let Some(Ok(cmd)) = inbox_iter.next() {
+        match cmd {
+            Cmd::Accept(context_id, bytes, output) => {
+                thread_state.queued.fetch_sub(1, Ordering::SeqCst);
+                let context = contexts
+                    .entry(context_id)
+                    .or_insert_with(gssapi::GSSContext::new);
+                let response =
+                    match acquire_credential(&mut credential, &settings.service_principal) {
+                        Ok(cred) => Msg::from(
+                            gssapi::accept_sec_context(
+                                context,
+                                cred,
+                                &gssapi::AppBuffer::from(&bytes),
+                            ),
+                            context_id,
+                            &settings,
+                        ),
+                        Err(e) => Msg::Failed(e),
+                    };
+                // A failed context can't continue, the client has to start over
+                if let Msg::Failed(_) = response {
+                    contexts.remove(&context_id);
+                }
+                // The client might have gone away already, that's fine
+                let _ = output.send(response);
+            }
+            Cmd::Release(context_id) => {
+                contexts.remove(&context_id);
+            }
+            Cmd::VerifyPassword(user, password, output) => {
+                thread_state.queued.fetch_sub(1, Ordering::SeqCst);
+                let response = match verify_password(&mut credential, &settings, &user, &password)
+                    .and_then(|(name, lifetime)| Ok((identity(&name, &settings)?, lifetime)))
+                {
+                    Ok((identity, lifetime)) => Msg::Accepted(vec![], identity, lifetime),
+                    Err(e) => Msg::Failed(e),
+                };
+                let _ = output.send(response);
+            }
+            Cmd::Initiate(user, delegated, output) => {
+                thread_state.queued.fetch_sub(1, Ordering::SeqCst);
+                let response = initiate(&mut initiator, &settings, &user, delegated.as_deref());
+                if response.is_err() {
+                    // Our own ticket might have expired, get a fresh one next time
+                    initiator.impersonator = None;
+                }
+                let _ = output.send(response);
+            }
+        }
     }
     debug!("Stopping thread");
 }
+
+fn verify_password(
+    credential: &mut Option<gssapi::GSSCredential>,
+    settings: &WorkerSettings,
+    user: &str,
+    password: &str,
+) -> Result<(gssapi::GSSName, Option<Duration>), GSSError> {
+    let user = gssapi::GSSName::import_principal(user)?;
+    let service = gssapi::GSSName::import_service(settings.service_principal.as_ref().unwrap())?;
+    let acceptor = acquire_credential(credential, &settings.service_principal)?;
+    gssapi::verify_password(&user, password, &service, acceptor)
+}
+
+// Impersonated credentials cached per thread; each also keeps the user's
+// ticket for the backend principal. Cleared when full.
+const MAX_CACHED_CREDENTIALS: usize = 1024;
+
+// Initiator credentials kept between requests: our own (for S4U2Self) and
+// the impersonated users'
+#[derive(Default)]
+struct Initiator {
+    impersonator: Option<gssapi::GSSCredential>,
+    users: HashMap<String, gssapi::GSSCredential>,
+}
+
+impl Initiator {
+    fn impersonate(
+        &mut self,
+        settings: &WorkerSettings,
+        user: &str,
+    ) -> Result<gssapi::GSSCredential, GSSError> {
+        if self.impersonator.is_none() {
+            let name = settings.service_principal.as_ref().unwrap();
+            let name = gssapi::GSSName::import_service(name)?;
+            let keytab = settings.keytab.as_ref().and_then(|k| k.to_str());
+            self.impersonator = Some(gssapi::GSSCredential::acquire_initiator_from_keytab(
+                &name, keytab,
+            )?);
+        }
+        let user = gssapi::GSSName::import_principal(user)?;
+        self.impersonator.as_ref().unwrap().impersonate(&user)
+    }
+}
+
+// Initial token of a context with the backend, with credentials of `user`
+// taken from their delegated ccache or obtained through S4U2Self. Without
+// mutual authentication there's nothing to continue, so the context is
+// dropped right away.
+fn initiate(
+    initiator: &mut Initiator,
+    settings: &WorkerSettings,
+    user: &str,
+    delegated: Option<&Ccache>,
+) -> Result<Vec<u8>, GSSError> {
+    match settings.backend_auth {
+        BackendAuth::None => unreachable!("initiating without backend_auth"),
+        // Tickets for the backend get cached in the ccache itself
+        BackendAuth::Delegated => match delegated {
+            Some(ccache) => backend_token(
+                &gssapi::GSSCredential::acquire_initiator_from_ccache(&ccache.name())?,
+                settings,
+            ),
+            None => Err(GSSError::no_delegated_credentials()),
+        },
+        BackendAuth::Impersonate => {
+            if let Some(cached) = initiator.users.get(user) {
+                match backend_token(cached, settings) {
+                    Ok(token) => return Ok(token),
+                    // Probably expired, start over
+                    Err(e) => debug!("Cached credentials of {} failed: {}", user, e),
+                }
+                initiator.users.remove(user);
+            }
+            let credential = initiator.impersonate(settings, user)?;
+            let token = backend_token(&credential, settings)?;
+            if initiator.users.len() >= MAX_CACHED_CREDENTIALS {
+                initiator.users.clear();
+            }
+            initiator.users.insert(String::from(user), credential);
+            Ok(token)
+        }
+    }
+}
+
+fn backend_token(
+    credential: &gssapi::GSSCredential,
+    settings: &WorkerSettings,
+) -> Result<Vec<u8>, GSSError> {
+    let target = gssapi::GSSName::import_service(settings.backend_principal.as_ref().unwrap())?;
+    let mut context = gssapi::GSSContext::new();
+    match gssapi::init_sec_context(
+        &mut context,
+        Some(credential),
+        &target,
+        gssapi::ContextFlags::default(),
+        None,
+    )? {
+        gssapi::InitResult::ContinueNeeded(buf) | gssapi::InitResult::Complete(buf) => {
+            Ok(Vec::from(buf.as_bytes()))
+        }
+    }
+}
+
+// Credentials are acquired lazily, and only if a specific principal
+// was requested. Otherwise GSS_C_NO_CREDENTIAL does the job.
+fn acquire_credential<'a>(
+    credential: &'a mut Option<gssapi::GSSCredential>,
+    service_principal: &Option<String>,
+) -> Result<Option<&'a gssapi::GSSCredential>, GSSError> {
+    let name = match service_principal {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    if credential.is_none() {
+        let name = gssapi::GSSName::import_service(name)?;
+        *credential = Some(gssapi::GSSCredential::acquire_acceptor(Some(&name))?);
+    }
+    Ok(credential.as_ref())
+}
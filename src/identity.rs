@@ -0,0 +1,196 @@
+use super::delegation::Ccache;
+use regex::Regex;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Who a client authenticated as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    /// Kerberos principal, like `alice@EXAMPLE.COM`
+    pub principal: String,
+    /// Name forwarded to the backend, after local name mapping and rewrites
+    pub name: String,
+    /// Groups from the configured group resolver, if any
+    pub groups: Vec<String>,
+    /// Credentials delegated by the client during this session's handshake
+    pub delegated: Option<Arc<Ccache>>,
+}
+
+impl Identity {
+    /// Map `principal` to the forwarded name: start from its local name
+    /// (if there is one) and apply the rewrites in order.
+    pub fn new(principal: String, local_name: Option<String>, rewrites: &[Rewrite]) -> Identity {
+        let name = rewrites.iter().fold(
+            local_name.unwrap_or_else(|| principal.clone()),
+            |name, r| r.apply(&name),
+        );
+        Identity {
+            principal,
+            name,
+            groups: vec![],
+            delegated: None,
+        }
+    }
+}
+
+/// A single rewrite of the forwarded name.
+///
+/// Textual form is `strip-realm`, `lowercase` or `s/REGEX/REPLACEMENT/`,
+/// with `$1`, `$name` etc. in REPLACEMENT referring to capture groups.
+/// A `/` in REGEX or REPLACEMENT is written as `\/`.
+#[derive(Debug, Clone)]
+pub enum Rewrite {
+    StripRealm,
+    Lowercase,
+    Substitute(Regex, String),
+}
+
+impl Rewrite {
+    fn apply(&self, name: &str) -> String {
+        match self {
+            Rewrite::StripRealm => match name.rfind('@') {
+                Some(i) => String::from(&name[..i]),
+                None => String::from(name),
+            },
+            Rewrite::Lowercase => name.to_lowercase(),
+            Rewrite::Substitute(re, replacement) => {
+                String::from(re.replace(name, replacement.as_str()))
+            }
+        }
+    }
+}
+
+impl FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rewrite, String> {
+        match s {
+            "strip-realm" => Ok(Rewrite::StripRealm),
+            "lowercase" => Ok(Rewrite::Lowercase),
+            _ if s.starts_with("s/") => match split_substitution(&s[2..])[..] {
+                [ref regex, ref replacement, ref rest] if rest.is_empty() => Regex::new(regex)
+                    .map(|re| Rewrite::Substitute(re, replacement.clone()))
+                    .map_err(|e| format!("Invalid rewrite regex {:?}: {}", regex, e)),
+                _ => Err(format!(
+                    "Invalid substitution {:?}, expected s/REGEX/REPLACEMENT/",
+                    s
+                )),
+            },
+            other => Err(format!(
+                "Invalid name rewrite {:?}, expected strip-realm, lowercase or s/REGEX/REPLACEMENT/",
+                other
+            )),
+        }
+    }
+}
+
+// "a\/b/c/" is ["a/b", "c", ""]: split at slashes, except escaped ones,
+// which lose the backslash. Other escapes are the regex's.
+fn split_substitution(s: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some('/') => part.push('/'),
+                Some(escaped) => {
+                    part.push('\\');
+                    part.push(escaped);
+                }
+                None => part.push('\\'),
+            },
+            '/' => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+    parts
+}
+
+impl<'de> Deserialize<'de> for Rewrite {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rewrite, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(principal: &str, local_name: Option<&str>, rules: &[&str]) -> String {
+        let rewrites: Vec<Rewrite> = rules.iter().map(|r| r.parse().unwrap()).collect();
+        Identity::new(
+            String::from(principal),
+            local_name.map(String::from),
+            &rewrites,
+        )
+        .name
+    }
+
+    #[test]
+    fn strip_realm() {
+        assert_eq!(
+            rewrite("alice@EXAMPLE.COM", None, &["strip-realm"]),
+            "alice"
+        );
+        assert_eq!(rewrite("a@b@EXAMPLE.COM", None, &["strip-realm"]), "a@b");
+        assert_eq!(
+            rewrite("alice@EXAMPLE.COM", Some("alice"), &["strip-realm"]),
+            "alice"
+        );
+    }
+
+    #[test]
+    fn lowercase() {
+        assert_eq!(
+            rewrite("Alice@EXAMPLE.COM", None, &["lowercase"]),
+            "alice@example.com"
+        );
+        assert_eq!(
+            rewrite("Alice@EXAMPLE.COM", None, &["strip-realm", "lowercase"]),
+            "alice"
+        );
+    }
+
+    #[test]
+    fn substitute() {
+        let rules = &[r"s/^(?P<user>[^@]+)@(.*)$/$2\$user/"];
+        assert_eq!(rewrite("alice@EXAMPLE", None, rules), r"EXAMPLE\alice");
+        // Only the first match
+        assert_eq!(rewrite("a.b.c", None, &[r"s/\.//"]), "ab.c");
+        // No match leaves the name alone
+        assert_eq!(rewrite("alice", None, &["s/@.*//"]), "alice");
+        // Rewrites apply in order, to the local name if there is one
+        assert_eq!(
+            rewrite(
+                "alice@EXAMPLE.COM",
+                Some("alice"),
+                &["s/^/x-/", "lowercase"]
+            ),
+            "x-alice"
+        );
+    }
+
+    #[test]
+    fn escaped_slashes() {
+        assert_eq!(rewrite("a/b@R", None, &[r"s/a\/b/c/"]), "c@R");
+        assert_eq!(
+            rewrite("host/h@R", None, &[r"s/^([^\/]+)\/(.*)$/$2\/$1/"]),
+            "h@R/host"
+        );
+        // An escaped backslash doesn't escape the slash after it
+        assert_eq!(rewrite(r"a\b", None, &[r"s/\\/\//"]), "a/b");
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in &[
+            "", "strip", "s/", "s/a/", "s/a/b", "s/a/b/c/", r"s/a\/b/", "s/(/x/", "y/a/b/",
+        ] {
+            assert!(rule.parse::<Rewrite>().is_err(), "{:?} parsed", rule);
+        }
+    }
+}
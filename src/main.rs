@@ -1,43 +1,99 @@
-#![feature(rust_2018_preview, type_ascription, never_type)]
 #[macro_use]
 extern crate log;
 #[macro_use]
-extern crate structopt;
+extern crate serde_derive;
 
-use std::sync::{Arc, Mutex};
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
+mod access_log;
+mod authorization;
 mod configuration;
+mod credentials;
+mod delegation;
+mod forwarding;
+mod groups;
 mod gssapi;
 mod gssapi_worker;
-use self::configuration::Configuration;
-use self::gssapi_worker::GSSWorker;
+mod identity;
+mod metrics;
+mod routing;
+mod session_cookie;
+mod timeout;
+mod upstream;
+use self::access_log::{AccessLog, AuthOutcome, LoggedBody, Principal, UpstreamLatency};
+use self::configuration::{Configuration, GroupSource};
+use self::credentials::{parse_authorization_header, Credentials};
+use self::groups::{CachedGroups, GroupResolver};
+use self::gssapi_worker::{BackendAuth, GSSWorkerPool, WorkerSettings};
+use self::identity::Identity;
+use self::metrics::{Metrics, RouteLabel};
+use self::routing::Router;
+use self::session_cookie::SessionCookies;
+use self::timeout::{IdleTimeout, InFlight};
+use self::upstream::{Pool, Upstreams};
 use futures::prelude::*;
-use structopt::StructOpt;
 
+use hyper::body::Payload;
 use hyper::client::{Client, HttpConnector};
+use hyper::server::conn::{AddrIncoming, Http};
 use hyper::service::Service;
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::{Body, Request, Response, StatusCode};
 use hyper_tls::HttpsConnector;
-use native_tls::TlsConnector;
+use native_tls::{TlsAcceptor, TlsConnector};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::{Delay, Interval, Timeout};
 
 #[derive(Debug)]
 struct ClientSession {
     state: AuthState,
     app_state: &'static AppState,
+    peer: SocketAddr,
+}
+
+// Address of the client, put in the extensions of every request
+#[derive(Debug, Clone, Copy)]
+struct ClientAddr(SocketAddr);
+
+fn client_addr<B>(req: &Request<B>) -> Option<SocketAddr> {
+    req.extensions().get::<ClientAddr>().map(|a| a.0)
+}
+
+fn client_name<B>(req: &Request<B>) -> String {
+    client_addr(req).map_or_else(|| String::from("unknown"), |a| a.to_string())
 }
 
 #[derive(Debug)]
 struct AppState {
     http_client: HttpClient,
+    gss_pool: GSSWorkerPool,
+    session_cookies: Option<SessionCookies>,
+    router: Router,
+    upstreams: Upstreams,
+    user_header: http::header::HeaderName,
+    delegation_header: http::header::HeaderName,
+    groups: Option<CachedGroups>,
+    groups_header: http::header::HeaderName,
+    access_log: Option<AccessLog>,
+    metrics: Metrics,
     configuration: Configuration,
 }
 
-struct ClientService(Arc<Mutex<ClientSession>>);
+struct ClientService {
+    session: Arc<Mutex<ClientSession>>,
+    in_flight: InFlight,
+}
 
 #[derive(Debug)]
 enum AuthState {
-    InProgress(GSSWorker),
-    Ok(String),
+    New,
+    // The worker holds the GSS context, Instant is when the handshake started
+    InProgress(gssapi_worker::GSSWorker, Instant),
+    // When the client has to authenticate again (if ever), and when its
+    // groups were looked up
+    Ok(Identity, Option<Instant>, Instant),
 }
 
 enum Either<L, R> {
@@ -45,142 +101,658 @@ enum Either<L, R> {
     Right(R),
 }
 
-type BoxFuture<I> = Box<Future<Item = I, Error = String> + Send>;
-type ResponseFuture = Future<Item = HttpResponse, Error = String> + Send;
+type BoxFuture<I> = Box<dyn Future<Item = I, Error = String> + Send>;
+type ResponseFuture = dyn Future<Item = HttpResponse, Error = String> + Send;
 type HttpClient = Client<HttpsConnector<HttpConnector>>;
 type HttpRequest = Request<Body>;
 type HttpResponse = Response<Body>;
+// Output token, identity and credentials lifetime of a finished handshake
+type Authenticated = (Vec<u8>, Identity, Option<Duration>);
 
-fn new_session(app_state: &'static AppState) -> ClientService {
-    let worker = GSSWorker::new();
-    ClientService(Arc::new(Mutex::new(ClientSession {
-        state: AuthState::InProgress(worker),
-        app_state,
-    })))
+fn new_session(app_state: &'static AppState, peer: SocketAddr) -> ClientService {
+    ClientService {
+        session: Arc::new(Mutex::new(ClientSession {
+            state: AuthState::New,
+            app_state,
+            peer,
+        })),
+        in_flight: InFlight::default(),
+    }
 }
 
-impl hyper::service::NewService for &'static AppState {
-    type Service = ClientService;
-    type ReqBody = <ClientService as Service>::ReqBody;
-    type ResBody = <ClientService as Service>::ResBody;
-    type Error = <ClientService as Service>::Error;
-    type Future = futures::future::FutureResult<ClientService, !>;
-    type InitError = !;
-
-    fn new_service(&self) -> Self::Future {
-        futures::done(Ok(new_session(*self)))
+// Reserve a GSS context for a new handshake, and make sure it gets
+// released if the client doesn't finish it in time.
+// Returns None when the worker pool is saturated.
+fn start_authentication(
+    session_m: &Arc<Mutex<ClientSession>>,
+    app: &AppState,
+) -> Option<AuthState> {
+    let worker = app.gss_pool.checkout()?;
+    app.metrics.handshake_started("negotiate");
+    let started = Instant::now();
+    if let Some(limit) = configuration::timeout(app.configuration.auth_timeout) {
+        let session_w: Weak<Mutex<ClientSession>> = Arc::downgrade(session_m);
+        tokio::spawn(Delay::new(started + limit).then(move |_| {
+            if let Some(session_m) = session_w.upgrade() {
+                let mut session = session_m.lock().unwrap();
+                match session.state {
+                    AuthState::InProgress(_, s) if s == started => {
+                        info!("Authentication of {} timed out", session.peer);
+                        session.state = AuthState::New;
+                    }
+                    _ => (),
+                }
+            }
+            Ok(())
+        }));
     }
+    Some(AuthState::InProgress(worker, started))
 }
 
 impl Service for ClientService {
     // this is the body gives you
     type ReqBody = hyper::Body;
-    // you could change this to a custom `Payload` impl if you wanted
-    type ResBody = hyper::Body;
+    // counts the bytes sent for the access log
+    type ResBody = LoggedBody;
     // can be any `E: std::error::Error`
     type Error = String;
     // doesn't have to Box<Future>, it's just easier if you return different
     // future types depending on conditions
-    type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
+    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
 
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-        handle_request(self.0.clone(), req)
+        let guard = self.in_flight.start();
+        let (app, entry) = {
+            let session = self.session.lock().unwrap();
+            let app = session.app_state;
+            let entry = app
+                .access_log
+                .as_ref()
+                .map(|log| (access_log::Entry::new(&req, session.peer), log));
+            (app, entry)
+        };
+        Box::new(handle_request(self.session.clone(), req).then(move |r| {
+            drop(guard);
+            r.map(|response| finish_response(app, response, entry))
+        }))
     }
 }
 
-fn handle_request(session_m: Arc<Mutex<ClientSession>>, req: HttpRequest) -> Box<ResponseFuture> {
-    let authenticate = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| parse_authorization_header(h.to_str().unwrap()));
+// Count the response, and finish the access log entry with what the
+// handlers found out; it's written once the body is sent
+fn finish_response(
+    app: &AppState,
+    response: HttpResponse,
+    entry: Option<(access_log::Entry, &'static AccessLog)>,
+) -> Response<LoggedBody> {
+    let (parts, body) = response.into_parts();
+    let upstream_latency = parts.extensions.get::<UpstreamLatency>().map(|l| l.0);
+    app.metrics.request(
+        parts
+            .extensions
+            .get::<RouteLabel>()
+            .map_or("none", |r| r.0.as_str()),
+        parts.status.as_u16(),
+        upstream_latency,
+    );
+    let entry = entry.map(|(mut entry, log)| {
+        entry.status = parts.status.as_u16();
+        entry.principal = parts.extensions.get::<Principal>().map(|p| p.0.clone());
+        entry.auth = parts.extensions.get::<AuthOutcome>().cloned();
+        entry.upstream_latency = upstream_latency;
+        (entry, log)
+    });
+    Response::from_parts(parts, LoggedBody::new(body, entry))
+}
+
+fn handle_request(
+    session_m: Arc<Mutex<ClientSession>>,
+    mut req: HttpRequest,
+) -> Box<ResponseFuture> {
+    let (app_state, peer) = {
+        let session = session_m.lock().unwrap();
+        (session.app_state, session.peer)
+    };
+    req.extensions_mut().insert(ClientAddr(peer));
+    // Everything below, from rules to the backend, sees the canonical path
+    match normalize_uri(req.uri()) {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(e) => {
+            info!("Rejecting request from {}: {}", peer, e);
+            return Box::new(futures::done(Ok(bad_request_response(
+                "Invalid request path",
+            ))));
+        }
+    }
+    let public = authorization::is_public(
+        &app_state.configuration.public_paths,
+        app_state.configuration.public_preflight,
+        &req,
+    );
+    if public {
+        debug!(
+            "{} {} is public, for {}",
+            req.method(),
+            req.uri().path(),
+            peer
+        );
+        return Box::new(
+            proxy_request(req, app_state, None, &[]).map(|mut response| {
+                response.extensions_mut().insert(AuthOutcome::Public);
+                response
+            }),
+        );
+    }
+
+    // Other schemes, and malformed values of them, are the backend's business
+    let authenticate = if is_for_proxy(app_state, req.headers()) {
+        match parse_authorization_header(req.headers()[http::header::AUTHORIZATION].as_bytes()) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                info!("Malformed Authorization header from {}: {}", peer, e);
+                let mut response = bad_request_response("Malformed Authorization header");
+                response.extensions_mut().insert(AuthOutcome::Failed);
+                return Box::new(futures::done(Ok(response)));
+            }
+        }
+    } else {
+        None
+    };
     trace!("Authorization: {:?}", authenticate);
 
-    Box::new(
+    let (outcome, handled): (AuthOutcome, BoxFuture<(Option<AuthState>, HttpResponse)>) = {
+        let session_mm = session_m.clone();
+        let mut session = session_mm.lock().unwrap();
+        let app_state = session.app_state;
+        let expired = match session.state {
+            AuthState::Ok(_, Some(expires), _) => Instant::now() >= expires,
+            _ => false,
+        };
+        if expired {
+            if let AuthState::Ok(ref user, ..) = session.state {
+                info!(
+                    "Session of {} from {} expired, authenticating again",
+                    user.principal, peer
+                );
+            }
+            session.state = AuthState::New;
+        }
+        // Basic credentials are sent with every request, so a different user's
+        // can show up on an authenticated connection
+        let switched_user = match (&authenticate, &session.state) {
+            (Some(Credentials::Basic(name, _)), AuthState::Ok(user, ..))
+                if !is_same_user(name, user) =>
+            {
+                info!(
+                    "{} from {} sent Basic credentials of {}, authenticating again",
+                    user.principal, peer, name
+                );
+                true
+            }
+            _ => false,
+        };
+        if switched_user {
+            session.state = AuthState::New;
+        }
+        // The cookie is the previous user's, too
+        let resumed = match session.state {
+            AuthState::Ok(..) => None,
+            _ if switched_user => None,
+            _ => app_state
+                .session_cookies
+                .as_ref()
+                .and_then(|c| c.verify(req.headers())),
+        };
+        if let Some((ref user, _)) = resumed {
+            debug!("Resuming session of {} from cookie", user.principal);
+        }
+        let timed_out = match session.state {
+            AuthState::InProgress(_, started) if resumed.is_none() => {
+                configuration::timeout(app_state.configuration.auth_timeout)
+                    .is_some_and(|limit| started.elapsed() >= limit)
+            }
+            _ => false,
+        };
+        let mut overloaded = false;
+        if timed_out {
+            info!("Authentication of {} timed out", peer);
+            session.state = AuthState::New;
+        } else if let (None, Some(Credentials::Negotiate(_)), AuthState::New) =
+            (&resumed, &authenticate, &session.state)
         {
-            let session_mm = session_m.clone();
-            let session = session_mm.lock().unwrap();
-            let app_state = session.app_state;
-            match (&authenticate, &session.state) {
-                (Some(token), AuthState::InProgress(gss_worker)) => Box::new(
-                    continue_authentication(gss_worker, token).and_then(move |r| match r {
-                        Either::Left((output, user)) => Box::new(
-                            proxy_request(req, app_state, &user, &output)
-                                .map(|response| (Some(AuthState::Ok(user)), response)),
-                        )
-                            as Box<dyn Future<Item = _, Error = _> + Send>,
-                        Either::Right(response) => Box::new(futures::done(Ok((None, response))))
-                            as Box<dyn Future<Item = _, Error = _> + Send>,
-                    }),
-                ),
-                (None, AuthState::InProgress(_)) => {
-                    Box::new(futures::done(Ok((None, authorization_request(&[])))))
-                        as Box<dyn Future<Item = _, Error = _> + Send>
-                }
-                (_, AuthState::Ok(user)) => Box::new(
-                    proxy_request(req, &session.app_state, &user, &[])
-                        .map(|response| (None, response)),
+            match start_authentication(&session_m, app_state) {
+                Some(state) => session.state = state,
+                None => overloaded = true,
+            }
+        }
+        match (&authenticate, &session.state) {
+            _ if timed_out => (
+                AuthOutcome::TimedOut,
+                Box::new(futures::done(Ok((None, authentication_timeout(app_state))))),
+            ),
+            _ if overloaded => (
+                AuthOutcome::Overloaded,
+                Box::new(futures::done(Ok((None, overloaded_response())))),
+            ),
+            _ if resumed.is_some() => {
+                let (user, cookie_left) = resumed.unwrap();
+                let valid_for = session_lifetime(app_state, Some(cookie_left));
+                (
+                    AuthOutcome::Cookie,
+                    start_session(req, app_state, user, vec![], None, valid_for),
                 )
-                    as Box<dyn Future<Item = _, Error = _> + Send>,
             }
-        }.and_then(move |(state, response)| {
-            let mut sess = session_m.lock().unwrap();
-            debug!("Setting state {:?}", state);
-            if let Some(s) = state {
-                sess.state = s;
+            (
+                Some(Credentials::Basic(user, password)),
+                AuthState::New | AuthState::InProgress(..),
+            ) => (AuthOutcome::Basic, {
+                app_state.metrics.handshake_started("basic");
+                Box::new(
+                    app_state
+                        .gss_pool
+                        .verify_password(user, password)
+                        .map(move |r| authentication_result(app_state, peer, "basic", r))
+                        .and_then(move |r| match r {
+                            Either::Left((_, user, lifetime)) => {
+                                let valid_for = session_lifetime(app_state, lifetime);
+                                let cookie = app_state
+                                    .session_cookies
+                                    .as_ref()
+                                    .map(|c| c.issue(&user, valid_for));
+                                start_session(req, app_state, user, vec![], cookie, valid_for)
+                            }
+                            Either::Right(response) => {
+                                Box::new(futures::done(Ok((state_after(&response), response))))
+                                    as Box<dyn Future<Item = _, Error = _> + Send>
+                            }
+                        }),
+                )
+            }),
+            (Some(Credentials::Negotiate(token)), AuthState::InProgress(gss_worker, _)) => (
+                AuthOutcome::Negotiate,
+                Box::new(
+                    continue_authentication(gss_worker, token, app_state, peer).and_then(
+                        move |r| match r {
+                            Either::Left((output, user, lifetime)) => {
+                                let valid_for = session_lifetime(app_state, lifetime);
+                                let cookie = app_state
+                                    .session_cookies
+                                    .as_ref()
+                                    .map(|c| c.issue(&user, valid_for));
+                                start_session(req, app_state, user, output, cookie, valid_for)
+                            }
+                            Either::Right(response) => {
+                                Box::new(futures::done(Ok((state_after(&response), response))))
+                                    as Box<dyn Future<Item = _, Error = _> + Send>
+                            }
+                        },
+                    ),
+                ),
+            ),
+            // Groups can change during a long session, look them up again
+            (_, AuthState::Ok(user, expires, resolved))
+                if app_state.groups.is_some()
+                    && resolved.elapsed()
+                        >= Duration::from_secs(app_state.configuration.groups_ttl) =>
+            {
+                debug!("Refreshing groups of {}", user.principal);
+                let valid_for = expires.map(|e| e.saturating_duration_since(Instant::now()));
+                (
+                    AuthOutcome::Session,
+                    start_session(req, app_state, user.clone(), vec![], None, valid_for),
+                )
             }
-            Ok(response)
-        }),
-    )
+            (_, AuthState::Ok(user, ..)) => (
+                AuthOutcome::Session,
+                Box::new(
+                    authorize_and_proxy(req, session.app_state, user, &[])
+                        .map(|response| (None, response)),
+                ),
+            ),
+            _ => (
+                AuthOutcome::Challenge,
+                Box::new(futures::done(Ok((
+                    None,
+                    authorization_request(app_state, &[]),
+                )))),
+            ),
+        }
+    };
+    Box::new(handled.and_then(move |(state, mut response)| {
+        let mut sess = session_m.lock().unwrap();
+        debug!("Setting state {:?}", state);
+        if let Some(s) = state {
+            sess.state = s;
+        }
+        if let AuthState::Ok(ref user, ..) = sess.state {
+            response
+                .extensions_mut()
+                .insert(Principal(user.principal.clone()));
+        }
+        // Unless a handler said why the request failed
+        if response.extensions().get::<AuthOutcome>().is_none() {
+            response.extensions_mut().insert(outcome);
+        }
+        Ok(response)
+    }))
+}
+
+// Look up the groups of a freshly authenticated client, then handle
+// the request as them. Returns the new session state with the response.
+fn start_session(
+    req: HttpRequest,
+    app: &'static AppState,
+    user: Identity,
+    authenticate: Vec<u8>,
+    cookie: Option<http::header::HeaderValue>,
+    valid_for: Option<Duration>,
+) -> BoxFuture<(Option<AuthState>, HttpResponse)> {
+    let expires = valid_for.map(|d| Instant::now() + d);
+    Box::new(resolve_groups(app, user).and_then(move |r| match r {
+        Either::Left(user) => Box::new(authorize_and_proxy(req, app, &user, &authenticate).map(
+            move |mut response| {
+                if let Some(c) = cookie {
+                    response.headers_mut().append(http::header::SET_COOKIE, c);
+                }
+                (Some(AuthState::Ok(user, expires, Instant::now())), response)
+            },
+        )) as Box<dyn Future<Item = _, Error = _> + Send>,
+        Either::Right(response) => Box::new(futures::done(Ok((None, response))))
+            as Box<dyn Future<Item = _, Error = _> + Send>,
+    }))
+}
+
+// A failed handshake starts over, releasing its context, so the client
+// can retry or fall back to Basic on the same connection
+fn state_after(response: &HttpResponse) -> Option<AuthState> {
+    match response.extensions().get::<AuthOutcome>() {
+        Some(AuthOutcome::Failed) => Some(AuthState::New),
+        _ => None,
+    }
+}
+
+// Whether Basic credentials of `name` are for the session's user: the full
+// principal, or the principal without its realm
+fn is_same_user(name: &str, user: &Identity) -> bool {
+    name == user.principal
+        || (!name.contains('@') && user.principal.rsplit_once('@').map(|(n, _)| n) == Some(name))
+}
+
+// How long a session lasts: as long as the client's credentials (ticket or
+// cookie), up to the configured session_lifetime
+fn session_lifetime(app: &AppState, credentials: Option<Duration>) -> Option<Duration> {
+    match (
+        credentials,
+        configuration::timeout(app.configuration.session_lifetime),
+    ) {
+        (Some(credentials), Some(limit)) => Some(credentials.min(limit)),
+        (credentials, limit) => credentials.or(limit),
+    }
+}
+
+// Group lookups can block, so they run on the runtime's blocking threads
+fn resolve_groups(
+    app: &'static AppState,
+    user: Identity,
+) -> BoxFuture<Either<Identity, HttpResponse>> {
+    let resolver = match app.groups {
+        Some(ref resolver) => resolver,
+        None => return Box::new(futures::done(Ok(Either::Left(user)))),
+    };
+    let lookup_user = user.clone();
+    let lookup = futures::future::poll_fn(move || {
+        tokio_threadpool::blocking(|| resolver.groups(&lookup_user)).map_err(|e| e.to_string())
+    });
+    Box::new(lookup.then(move |r| match r {
+        Ok(Ok(groups)) => {
+            debug!("Groups of {}: {:?}", user.principal, groups);
+            Ok(Either::Left(Identity { groups, ..user }))
+        }
+        Ok(Err(e)) | Err(e) => {
+            error!("Cannot look up groups of {}: {}", user.principal, e);
+            Ok(Either::Right(groups_unavailable_response()))
+        }
+    }))
 }
 
-fn authorization_request(token: &[u8]) -> HttpResponse {
-    let authenticate = if token.is_empty() {
-        String::from("Negotiate")
+fn authorization_request(app: &AppState, token: &[u8]) -> HttpResponse {
+    unauthorized_response(app, token, "No Authorization")
+}
+
+// 401 with the Negotiate challenge (continuing the handshake if there's
+// a token), and the Basic one when that's enabled
+fn unauthorized_response(app: &AppState, token: &[u8], message: &'static str) -> HttpResponse {
+    let mut builder = Response::builder();
+    builder.status(StatusCode::UNAUTHORIZED);
+    if token.is_empty() {
+        builder.header("WWW-Authenticate", "Negotiate");
+        if app.configuration.basic_auth {
+            builder.header(
+                "WWW-Authenticate",
+                format!("Basic realm=\"{}\"", app.configuration.basic_realm).as_bytes(),
+            );
+        }
     } else {
-        format!("Negotiate {}", base64::encode(token))
+        builder.header(
+            "WWW-Authenticate",
+            format!("Negotiate {}", base64::encode(token)).as_bytes(),
+        );
+    }
+    builder.body(Body::from(message)).unwrap()
+}
+
+fn overloaded_response() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", "1")
+        .body(Body::from("Too many authentications in progress"))
+        .unwrap()
+}
+
+fn normalize_uri(uri: &http::Uri) -> Result<http::Uri, String> {
+    // `OPTIONS *` has no path to normalize
+    if !uri.path().starts_with('/') {
+        return Ok(uri.clone());
+    }
+    let path = authorization::normalize_path(uri.path())?;
+    if path == uri.path() {
+        return Ok(uri.clone());
+    }
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
     };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .map_err(|e| format!("Invalid path {:?}: {}", path_and_query, e))?,
+    );
+    http::Uri::from_parts(parts).map_err(|e| format!("Invalid URI: {}", e))
+}
+
+fn bad_request_response(message: &'static str) -> HttpResponse {
     Response::builder()
-        .header("WWW-Authenticate", authenticate.as_bytes())
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Body::from("No Authorization"))
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(message))
         .unwrap()
 }
 
+// The backend would only turn the request down without the proxy's token
+fn backend_auth_failed(principal: &str, e: &dyn std::fmt::Display) -> Box<ResponseFuture> {
+    warn!("Cannot authenticate to the backend as {}: {}", principal, e);
+    let mut response = Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from("Cannot authenticate to the backend"))
+        .unwrap();
+    response.extensions_mut().insert(AuthOutcome::BackendFailed);
+    Box::new(futures::done(Ok(response)))
+}
+
+fn groups_unavailable_response() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from("Group lookup failed"))
+        .unwrap()
+}
+
+fn authentication_timeout(app: &AppState) -> HttpResponse {
+    unauthorized_response(app, &[], "Authentication timed out")
+}
+
 fn continue_authentication(
-    gss_worker: &GSSWorker,
+    gss_worker: &gssapi_worker::GSSWorker,
     token: &[u8],
-) -> BoxFuture<Either<(Vec<u8>, String), HttpResponse>> {
-    Box::new(gss_worker.accept_sec_context(token).and_then(|r| match r {
-        gssapi_worker::AcceptResult::Accepted(output, user) => Ok(Either::Left((output, user))),
+    app: &'static AppState,
+    peer: SocketAddr,
+) -> BoxFuture<Either<Authenticated, HttpResponse>> {
+    Box::new(
+        gss_worker
+            .accept_sec_context(token)
+            .map(move |r| authentication_result(app, peer, "negotiate", r)),
+    )
+}
+
+fn authentication_result(
+    app: &AppState,
+    peer: SocketAddr,
+    method: &str,
+    result: gssapi_worker::AcceptResult,
+) -> Either<Authenticated, HttpResponse> {
+    match result {
+        gssapi_worker::AcceptResult::Accepted(output, user, lifetime) => {
+            app.metrics.handshake_completed(method);
+            Either::Left((output, user, lifetime))
+        }
         gssapi_worker::AcceptResult::ContinueNeeded(output) => {
-            Ok(Either::Right(authorization_request(&output)))
+            let mut response = authorization_request(app, &output);
+            response.extensions_mut().insert(AuthOutcome::Continue);
+            Either::Right(response)
+        }
+        gssapi_worker::AcceptResult::Overloaded => {
+            let mut response = overloaded_response();
+            response.extensions_mut().insert(AuthOutcome::Overloaded);
+            Either::Right(response)
         }
         gssapi_worker::AcceptResult::Failed(err) => {
-            info!("Authentication failed: {}", err);
-            Response::builder()
-                .header("WWW-Authenticate", "Negotiate")
-                .status(StatusCode::UNAUTHORIZED)
-                .body(Body::from("Authentication failed"))
-                .map_err(|e| format!("{:?}", e))
-                .map(Either::Right)
+            info!("Authentication of {} failed: {}", peer, err);
+            app.metrics.handshake_failed(method, err.major_name());
+            let mut response = unauthorized_response(app, &[], "Authentication failed");
+            response.extensions_mut().insert(AuthOutcome::Failed);
+            Either::Right(response)
         }
-    }))
+    }
+}
+
+fn authorize_and_proxy(
+    req: HttpRequest,
+    app: &'static AppState,
+    user: &Identity,
+    authenticate: &[u8],
+) -> Box<ResponseFuture> {
+    let allowed = authorization::is_allowed(
+        &app.configuration.rules,
+        user,
+        req.method(),
+        req.uri().path(),
+    );
+    if allowed {
+        proxy_request(req, app, Some(user), authenticate)
+    } else {
+        info!(
+            "Denied {} {} for {} from {}",
+            req.method(),
+            req.uri().path(),
+            user.principal,
+            client_name(&req)
+        );
+        let mut response = forbidden_response(authenticate);
+        response.extensions_mut().insert(AuthOutcome::Denied);
+        Box::new(futures::done(Ok(response)))
+    }
+}
+
+// Whether the Authorization header uses a scheme the proxy handles itself,
+// so it's not passed on even where no authentication is needed
+fn is_for_proxy(app: &AppState, headers: &http::HeaderMap) -> bool {
+    let scheme = match headers.get(http::header::AUTHORIZATION) {
+        Some(value) => value.as_bytes().split(|&b| b == b' ').next().unwrap_or(b""),
+        None => return false,
+    };
+    scheme.eq_ignore_ascii_case(b"negotiate")
+        || (scheme.eq_ignore_ascii_case(b"basic") && app.configuration.basic_auth)
 }
 
 fn proxy_request(
     req: HttpRequest,
-    app: &AppState,
-    _user: &str,
+    app: &'static AppState,
+    user: Option<&Identity>,
     authenticate: &[u8],
 ) -> Box<ResponseFuture> {
-    let backend_uri = format!("{}{}", app.configuration.backend, req.uri());
-    info!("Requesting {}", backend_uri);
-    let new_request = builder_from_request(&req)
+    let route = match app.router.find(&req) {
+        Some(route) => route,
+        None => {
+            info!("No route for {}", req.uri());
+            return Box::new(futures::done(Ok(not_found_response())));
+        }
+    };
+    // Every backend got its pool at startup
+    let pool = app.upstreams.get(&route.backend).unwrap();
+    let backend_path = route.backend_path(req.uri());
+    info!(
+        "Requesting {}{} as {} for {}",
+        route.backend,
+        backend_path,
+        user.map_or("anonymous", |u| u.principal.as_str()),
+        client_name(&req)
+    );
+    let client = client_addr(&req);
+    let host = req
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority_part().map(|a| a.as_str()))
+        .map(String::from);
+    // The upstream gets picked (and put in the URI) when sending
+    let mut new_request = builder_from_request(&req)
         .version(http::Version::HTTP_11)
-        .uri(backend_uri)
+        .uri(backend_path.as_str())
         .body(req.into_body())
         .unwrap();
 
+    if let Some(ref cookies) = app.session_cookies {
+        cookies.strip(new_request.headers_mut());
+    }
+    forwarding::strip_hop_by_hop(new_request.headers_mut());
+    // The client's credentials were meant for us, not for the backend
+    if user.is_some() || is_for_proxy(app, new_request.headers()) {
+        new_request
+            .headers_mut()
+            .remove(http::header::AUTHORIZATION);
+    }
+    let proto = if app.configuration.tls_cert.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    forwarding::add_forwarding_headers(
+        new_request.headers_mut(),
+        client.map(|a| a.ip()),
+        proto,
+        host.as_deref(),
+    );
+    // Never trust the client's copies of the identity headers
+    forwarding::strip_with_variants(new_request.headers_mut(), &app.user_header);
+    forwarding::strip_with_variants(new_request.headers_mut(), &app.groups_header);
+    if app.configuration.delegation_dir.is_some() {
+        forwarding::strip_with_variants(new_request.headers_mut(), &app.delegation_header);
+    }
+    if let Some(user) = user {
+        if let Err(e) = add_identity_headers(new_request.headers_mut(), app, user) {
+            return Box::new(futures::done(Ok(error_response(&e))));
+        }
+    }
+
     let auth_header = if !authenticate.is_empty() {
         Some(
             http::header::HeaderValue::from_str(
@@ -191,19 +763,262 @@ fn proxy_request(
         None
     };
 
-    Box::new(
-        app.http_client
-            .request(new_request)
-            .or_else(|e| futures::done(Ok(error_response(&e))))
-            .map(|mut response| {
-                if let Some(val) = auth_header {
-                    response.headers_mut().insert("WWW-Authenticate", val);
+    let backend_response: Box<ResponseFuture> = match (app.configuration.backend_auth, user) {
+        (BackendAuth::None, _) | (_, None) => send_to_backend(new_request, pool, app),
+        (_, Some(user)) => {
+            let token = app.gss_pool.initiate(user);
+            let user = user.principal.clone();
+            Box::new(token.then(move |token| {
+                match token {
+                    Ok(gssapi_worker::InitiateResult::Token(token)) => {
+                        let val = format!("Negotiate {}", base64::encode(&token));
+                        new_request.headers_mut().insert(
+                            http::header::AUTHORIZATION,
+                            http::header::HeaderValue::from_str(&val).unwrap(),
+                        );
+                    }
+                    Ok(gssapi_worker::InitiateResult::Overloaded) => {
+                        let mut response = overloaded_response();
+                        response.extensions_mut().insert(AuthOutcome::Overloaded);
+                        return Box::new(futures::done(Ok(response))) as Box<ResponseFuture>;
+                    }
+                    Ok(gssapi_worker::InitiateResult::Failed(e)) => {
+                        return backend_auth_failed(&user, &e);
+                    }
+                    Err(e) => return backend_auth_failed(&user, &e),
                 }
-                *response.version_mut() = http::Version::HTTP_11;
+                send_to_backend(new_request, pool, app)
+            }))
+        }
+    };
+
+    let route_label = route.label();
+    Box::new(backend_response.map(|mut response| {
+        forwarding::strip_hop_by_hop(response.headers_mut());
+        response.extensions_mut().insert(RouteLabel(route_label));
+        if let Some(val) = auth_header {
+            response.headers_mut().insert("WWW-Authenticate", val);
+        }
+        *response.version_mut() = http::Version::HTTP_11;
+
+        response
+    }))
+}
+
+fn add_identity_headers(
+    headers: &mut http::HeaderMap,
+    app: &AppState,
+    user: &Identity,
+) -> Result<(), http::header::InvalidHeaderValue> {
+    headers.insert(
+        app.user_header.clone(),
+        http::header::HeaderValue::from_bytes(user.name.as_bytes())?,
+    );
+    if app.groups.is_some() {
+        match http::header::HeaderValue::from_bytes(user.groups.join(",").as_bytes()) {
+            Ok(val) => {
+                headers.insert(app.groups_header.clone(), val);
+            }
+            Err(_) => warn!("Cannot pass groups of {} in a header", user.principal),
+        }
+    }
+    // Only credentials delegated in this session, not by earlier ones
+    if let Some(ref ccache) = user.delegated {
+        if let Ok(val) = http::header::HeaderValue::from_str(&ccache.name()) {
+            headers.insert(app.delegation_header.clone(), val);
+        }
+    }
+    Ok(())
+}
+
+fn send_to_backend(
+    request: HttpRequest,
+    pool: &'static Pool,
+    app: &'static AppState,
+) -> Box<ResponseFuture> {
+    let replay = Replay::from(&request);
+    send_attempt(request, replay, pool, app, vec![])
+}
 
+// Send the request to one of the pool's upstreams, and to the next one
+// if that fails and the request can be safely repeated.
+fn send_attempt(
+    mut request: HttpRequest,
+    replay: Option<Replay>,
+    pool: &'static Pool,
+    app: &'static AppState,
+    mut tried: Vec<usize>,
+) -> Box<ResponseFuture> {
+    let i = match pool.pick(&tried) {
+        Some(i) => i,
+        None => {
+            error!("No upstream of {} available", pool.name);
+            return Box::new(futures::done(Ok(no_upstream_response())));
+        }
+    };
+    tried.push(i);
+    let uri = pool.upstreams[i].url_for(request.uri().path_and_query().map_or("/", |p| p.as_str()));
+    match uri.parse() {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(e) => return Box::new(futures::done(Ok(error_response(&e)))),
+    }
+    debug!("Sending request to {}", uri);
+    let active = pool.start(i);
+    let started = Instant::now();
+    Box::new(request_upstream(request, app).then(move |r| {
+        drop(active);
+        match r {
+            Ok(mut response) => {
+                pool.report_success(i);
                 response
+                    .extensions_mut()
+                    .insert(UpstreamLatency(started.elapsed()));
+                Box::new(futures::done(Ok(response))) as Box<ResponseFuture>
+            }
+            Err(e) => {
+                warn!("Request to {} failed: {}", pool.upstreams[i].url, e);
+                pool.report_failure(i);
+                match replay {
+                    Some(replay) if pool.pick(&tried).is_some() => {
+                        info!("Retrying on another upstream of {}", pool.name);
+                        let request = replay.request();
+                        send_attempt(request, Some(replay), pool, app, tried)
+                    }
+                    _ => Box::new(futures::done(Ok(match e {
+                        BackendError::TimedOut => gateway_timeout_response(),
+                        BackendError::Failed(_) => bad_gateway_response(),
+                    }))),
+                }
+            }
+        }
+    }))
+}
+
+#[derive(Debug)]
+enum BackendError {
+    TimedOut,
+    Failed(String),
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackendError::TimedOut => write!(f, "timed out"),
+            BackendError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn request_upstream(
+    request: HttpRequest,
+    app: &AppState,
+) -> Box<dyn Future<Item = HttpResponse, Error = BackendError> + Send> {
+    match configuration::timeout(app.configuration.backend_timeout) {
+        Some(limit) => Box::new(
+            Timeout::new(app.http_client.request(request), limit).map_err(|e| {
+                if e.is_elapsed() {
+                    BackendError::TimedOut
+                } else {
+                    BackendError::Failed(e.to_string())
+                }
             }),
-    )
+        ),
+        None => Box::new(
+            app.http_client
+                .request(request)
+                .map_err(|e| BackendError::Failed(e.to_string())),
+        ),
+    }
+}
+
+// What's needed to send a request again: it has to be idempotent, and
+// without a body (which is streamed, and gone after the first attempt)
+struct Replay {
+    method: http::Method,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+}
+
+impl Replay {
+    fn from(request: &HttpRequest) -> Option<Replay> {
+        let idempotent = matches!(
+            *request.method(),
+            http::Method::GET
+                | http::Method::HEAD
+                | http::Method::OPTIONS
+                | http::Method::TRACE
+                | http::Method::PUT
+                | http::Method::DELETE
+        );
+        let headers = request.headers();
+        // Transfer-Encoding is already stripped, but the body knows
+        let has_body = !request.body().is_end_stream()
+            || headers
+                .get(http::header::CONTENT_LENGTH)
+                .is_some_and(|l| l != "0");
+        if idempotent && !has_body {
+            Some(Replay {
+                method: request.method().clone(),
+                uri: request.uri().clone(),
+                headers: headers.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn request(&self) -> HttpRequest {
+        let mut request = Request::new(Body::empty());
+        *request.method_mut() = self.method.clone();
+        *request.uri_mut() = self.uri.clone();
+        *request.headers_mut() = self.headers.clone();
+        request
+    }
+}
+
+// Poll the health check path of every upstream in pools that have one
+fn start_health_checks(app: &'static AppState) {
+    for pool in app.upstreams.checked() {
+        let interval = Duration::from_secs(pool.settings.health_check_interval);
+        tokio::spawn(
+            Interval::new_interval(interval)
+                .map_err(|e| error!("Health check timer failed: {}", e))
+                .for_each(move |_| {
+                    for i in 0..pool.upstreams.len() {
+                        tokio::spawn(check_upstream(pool, i, app));
+                    }
+                    Ok(())
+                }),
+        );
+    }
+}
+
+fn check_upstream(
+    pool: &'static Pool,
+    i: usize,
+    app: &AppState,
+) -> impl Future<Item = (), Error = ()> {
+    let path = pool.settings.health_check_path.as_ref().unwrap();
+    let url = pool.upstreams[i].url_for(path);
+    let request = Request::get(url.as_str()).body(Body::empty()).unwrap();
+    let limit = Duration::from_secs(pool.settings.health_check_timeout);
+    Timeout::new(app.http_client.request(request), limit).then(move |r| {
+        let healthy = match r {
+            Ok(response) => {
+                let status = response.status();
+                if !status.is_success() && !status.is_redirection() {
+                    debug!("Health check of {} returned {}", url, status);
+                }
+                status.is_success() || status.is_redirection()
+            }
+            Err(e) => {
+                debug!("Health check of {} failed: {}", url, e);
+                false
+            }
+        };
+        pool.set_healthy(i, healthy);
+        Ok(())
+    })
 }
 
 fn builder_from_request(req: &HttpRequest) -> ::http::request::Builder {
@@ -216,6 +1031,46 @@ fn builder_from_request(req: &HttpRequest) -> ::http::request::Builder {
     r
 }
 
+fn forbidden_response(authenticate: &[u8]) -> HttpResponse {
+    let mut builder = Response::builder();
+    builder.status(StatusCode::FORBIDDEN);
+    if !authenticate.is_empty() {
+        builder.header(
+            "WWW-Authenticate",
+            format!("Negotiate {}", base64::encode(authenticate)).as_bytes(),
+        );
+    }
+    builder.body(Body::from("Forbidden")).unwrap()
+}
+
+fn not_found_response() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("Not found"))
+        .unwrap()
+}
+
+fn bad_gateway_response() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from("Bad gateway"))
+        .unwrap()
+}
+
+fn no_upstream_response() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from("No backend available"))
+        .unwrap()
+}
+
+fn gateway_timeout_response() -> HttpResponse {
+    Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .body(Body::from("Gateway timeout"))
+        .unwrap()
+}
+
 fn error_response<E: ::std::error::Error>(err: &E) -> HttpResponse {
     error!("Error when requesting {}", err);
     Response::builder()
@@ -224,16 +1079,14 @@ fn error_response<E: ::std::error::Error>(err: &E) -> HttpResponse {
         .unwrap()
 }
 
-fn parse_authorization_header(raw: &str) -> Option<Vec<u8>> {
-    if raw.starts_with("Negotiate ") {
-        base64::decode(&raw[10..]).ok()
-    } else {
-        None
-    }
-}
-
 fn main() {
-    let configuration = Configuration::from_args();
+    let configuration = match Configuration::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
     stderrlog::new()
         .module(module_path!())
@@ -246,18 +1099,273 @@ fn main() {
         .init()
         .unwrap();
 
+    if let Err(e) = setup_acceptor(&configuration) {
+        error!("Cannot set up the acceptor: {}", e);
+        std::process::exit(2);
+    }
+
     let tls_connector = build_tls_connector(&configuration).unwrap();
+    let tls_acceptor = match build_tls_acceptor(&configuration) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Cannot load the TLS certificate: {}", e);
+            std::process::exit(2);
+        }
+    };
     let http_client = build_http_client(tls_connector);
     let addr = configuration.bind.parse().unwrap();
-    let app_state = Box::new(AppState {
+    let router = Router::new(
+        configuration.routes.clone(),
+        Some(configuration.backend.as_str()).filter(|b| !b.is_empty()),
+    );
+    let upstreams = Upstreams::new(&configuration.pools, router.backends());
+    let user_header = configuration.user_header.parse().unwrap();
+    let delegation_header = configuration.delegation_header.parse().unwrap();
+    let groups_header = configuration.groups_header.parse().unwrap();
+    let groups = match build_group_resolver(&configuration) {
+        Ok(g) => g,
+        Err(e) => {
+            error!("Cannot set up group lookups: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let access_log = match build_access_log(&configuration) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Cannot open the access log: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let metrics = match Metrics::new() {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Cannot set up metrics: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let metrics_incoming =
+        configuration.metrics_bind.as_ref().map(|bind| {
+            match AddrIncoming::bind(&bind.parse().unwrap()) {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    error!("Cannot listen on {} for metrics: {}", bind, e);
+                    std::process::exit(2);
+                }
+            }
+        });
+    let session_cookies = match build_session_cookies(&configuration) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Cannot set up session cookies: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let gss_pool = GSSWorkerPool::new(
+        configuration.gss_threads,
+        configuration.max_handshakes,
+        configuration.gss_queue,
+        WorkerSettings {
+            service_principal: configuration.service_principal.clone(),
+            delegation_dir: configuration.delegation_dir.clone(),
+            keytab: configuration.keytab.clone(),
+            backend_auth: configuration.backend_auth,
+            backend_principal: configuration.backend_principal.clone(),
+            local_name: configuration.local_name,
+            name_rewrites: configuration.name_rewrites.clone(),
+            required_flags: configuration::context_flags(&configuration.required_flags),
+        },
+    );
+    let app_state: &'static AppState = Box::leak(Box::new(AppState {
         http_client,
+        gss_pool,
+        session_cookies,
+        router,
+        upstreams,
+        user_header,
+        delegation_header,
+        groups,
+        groups_header,
+        access_log,
+        metrics,
         configuration,
+    }));
+
+    let incoming = AddrIncoming::bind(&addr).unwrap().map(|stream| {
+        let peer = stream.remote_addr();
+        (stream, peer)
     });
+    match tls_acceptor {
+        None => {
+            info!("Listening on http://{}", addr);
+            run_server(incoming, metrics_incoming, app_state);
+        }
+        Some(acceptor) => {
+            let acceptor = tokio_tls::TlsAcceptor::from(acceptor);
+            // Clients that never finish the handshake would hold its slot forever
+            let handshake_timeout = configuration::timeout(app_state.configuration.idle_timeout)
+                .or_else(|| configuration::timeout(app_state.configuration.auth_timeout))
+                .unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT);
+            // Handshakes run concurrently, so a slow client can't block the listener
+            let incoming = incoming
+                .map(move |(stream, peer)| {
+                    let handshake = Timeout::new(acceptor.accept(stream), handshake_timeout)
+                        .map_err(|e| {
+                            if e.is_elapsed() {
+                                String::from("timed out")
+                            } else if e.is_inner() {
+                                e.into_inner().unwrap().to_string()
+                            } else {
+                                e.to_string()
+                            }
+                        });
+                    handshake.then(move |r| match r {
+                        Ok(s) => Ok(Some((s, peer))),
+                        Err(e) => {
+                            info!("TLS handshake with {} failed: {}", peer, e);
+                            Ok(None)
+                        }
+                    })
+                })
+                .buffer_unordered(TLS_HANDSHAKE_CONCURRENCY)
+                .filter_map(|s| s);
+            info!("Listening on https://{}", addr);
+            run_server(incoming, metrics_incoming, app_state);
+        }
+    }
+}
 
-    let server = Server::bind(&addr).serve(Box::leak(app_state) as &'static AppState);
+const TLS_HANDSHAKE_CONCURRENCY: usize = 128;
+// Used when neither the idle nor the authentication timeout is set
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
-    info!("Listening on http://{}", addr);
-    hyper::rt::run(server.map_err(|err| error!("server error: {}", err)));
+fn run_server<I, S>(incoming: I, metrics: Option<AddrIncoming>, app_state: &'static AppState)
+where
+    I: Stream<Item = (S, SocketAddr), Error = std::io::Error> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let http = Http::new();
+    let idle_timeout = configuration::timeout(app_state.configuration.idle_timeout);
+    // Connections are served one by one, so that the idle timeout can see
+    // whether the service is busy with a request
+    let server = incoming.for_each(move |(stream, peer)| {
+        trace!("Connection from {}", peer);
+        let service = new_session(app_state, peer);
+        let io = IdleTimeout::new(stream, idle_timeout, service.in_flight.clone());
+        app_state.metrics.connection_opened();
+        hyper::rt::spawn(http.serve_connection(io, service).then(move |r| {
+            app_state.metrics.connection_closed();
+            r.map_err(|e| debug!("Connection error: {}", e))
+        }));
+        Ok(())
+    });
+    hyper::rt::run(futures::lazy(move || {
+        start_health_checks(app_state);
+        if let Some(incoming) = metrics {
+            info!(
+                "Serving metrics on http://{}/metrics",
+                incoming.local_addr()
+            );
+            tokio::spawn(
+                hyper::Server::builder(incoming)
+                    .serve(move || {
+                        hyper::service::service_fn_ok(move |req| metrics_response(&req, app_state))
+                    })
+                    .map_err(|e| error!("Metrics listener failed: {}", e)),
+            );
+        }
+        server.map_err(|err| error!("server error: {}", err))
+    }));
+}
+
+fn metrics_response(req: &HttpRequest, app: &AppState) -> HttpResponse {
+    if req.uri().path() != "/metrics" {
+        return not_found_response();
+    }
+    match app.metrics.render(app.gss_pool.stats()) {
+        Ok((content_type, body)) => Response::builder()
+            .header(http::header::CONTENT_TYPE, content_type.as_str())
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => error_response(&e),
+    }
+}
+
+// Register the keytab and check that we can actually accept with it,
+// instead of failing on the first client.
+fn setup_acceptor(c: &Configuration) -> Result<(), String> {
+    if let Some(ref keytab) = c.keytab {
+        gssapi::register_acceptor_keytab(&keytab.to_string_lossy())?;
+    }
+    if let Some(ref dir) = c.delegation_dir {
+        delegation::create_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        // Clients' TGTs from before a crash would stay around forever
+        match delegation::remove_stale(dir) {
+            Ok(0) => (),
+            Ok(n) => info!("Removed {} stale ccaches from {}", n, dir.display()),
+            Err(e) => return Err(format!("{}: {}", dir.display(), e)),
+        }
+    }
+    if c.keytab.is_some() || c.service_principal.is_some() {
+        let name = match c.service_principal {
+            Some(ref p) => Some(gssapi::GSSName::import_service(p).map_err(|e| e.to_string())?),
+            None => None,
+        };
+        gssapi::GSSCredential::acquire_acceptor(name.as_ref()).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn build_access_log(c: &Configuration) -> Result<Option<AccessLog>, String> {
+    let path = match c.access_log {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+    let log = AccessLog::open(path, c.access_log_format)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if log.is_file() {
+        access_log::reopen_on_sighup();
+    }
+    Ok(Some(log))
+}
+
+fn build_session_cookies(c: &Configuration) -> Result<Option<SessionCookies>, String> {
+    if !c.session_cookie {
+        return Ok(None);
+    }
+    let key = match c.cookie_secret_file {
+        Some(ref path) => {
+            let key = std::fs::read(path)
+                .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            if key.len() < 32 {
+                return Err(format!("{}: use at least 32 bytes", path.display()));
+            }
+            key
+        }
+        None => {
+            let mut key = vec![0u8; 32];
+            rand::thread_rng().fill(&mut key[..]);
+            key
+        }
+    };
+    Ok(Some(SessionCookies::new(
+        key,
+        c.cookie_name.clone(),
+        Duration::from_secs(c.cookie_lifetime),
+        c.tls_cert.is_some(),
+    )))
+}
+
+fn build_group_resolver(c: &Configuration) -> Result<Option<CachedGroups>, String> {
+    let resolver: Box<dyn GroupResolver> = match c.groups {
+        GroupSource::None => return Ok(None),
+        GroupSource::File => Box::new(groups::StaticGroups::load(c.groups_file.as_ref().unwrap())?),
+        GroupSource::Nss => Box::new(groups::NssGroups),
+        GroupSource::Ldap => Box::new(groups::LdapGroups::new(c.ldap.clone().unwrap())?),
+    };
+    Ok(Some(CachedGroups::new(
+        resolver,
+        Duration::from_secs(c.groups_ttl),
+    )))
 }
 
 fn build_http_client(tls_connector: TlsConnector) -> HttpClient {
@@ -271,3 +1379,89 @@ fn build_tls_connector(c: &Configuration) -> Result<TlsConnector, native_tls::Er
     b.danger_accept_invalid_certs(c.tls_insecure);
     b.build()
 }
+
+fn build_tls_acceptor(c: &Configuration) -> Result<Option<TlsAcceptor>, String> {
+    let cert_path = match c.tls_cert {
+        Some(ref p) => p,
+        None => return Ok(None),
+    };
+    let cert = std::fs::read(cert_path)
+        .map_err(|e| format!("Cannot read {}: {}", cert_path.display(), e))?;
+    let identity = match c.tls_key {
+        Some(ref key_path) => {
+            let key = std::fs::read(key_path)
+                .map_err(|e| format!("Cannot read {}: {}", key_path.display(), e))?;
+            native_tls::Identity::from_pkcs8(&cert, &key)
+        }
+        None => native_tls::Identity::from_pkcs12(&cert, c.tls_password.as_ref().map_or("", |p| p)),
+    }
+    .map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+    TlsAcceptor::new(identity)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: http::Method, body: Body) -> HttpRequest {
+        let mut request = Request::new(body);
+        *request.method_mut() = method;
+        *request.uri_mut() = "/a?b=c".parse().unwrap();
+        request
+            .headers_mut()
+            .insert("x-test", http::header::HeaderValue::from_static("1"));
+        request
+    }
+
+    #[test]
+    fn replays_idempotent_requests_without_body() {
+        for method in &[
+            http::Method::GET,
+            http::Method::HEAD,
+            http::Method::OPTIONS,
+            http::Method::TRACE,
+            http::Method::PUT,
+            http::Method::DELETE,
+        ] {
+            let replay = Replay::from(&request(method.clone(), Body::empty()));
+            let again = replay.expect("not replayable").request();
+            assert_eq!(again.method(), method);
+            assert_eq!(again.uri(), "/a?b=c");
+            assert_eq!(again.headers()["x-test"], "1");
+        }
+    }
+
+    #[test]
+    fn doesnt_replay_other_methods() {
+        for method in &[
+            http::Method::POST,
+            http::Method::PATCH,
+            http::Method::CONNECT,
+        ] {
+            assert!(Replay::from(&request(method.clone(), Body::empty())).is_none());
+        }
+    }
+
+    #[test]
+    fn doesnt_replay_bodies() {
+        let with_body = request(http::Method::PUT, Body::from("data"));
+        assert!(Replay::from(&with_body).is_none());
+
+        // The body might not have been read yet
+        let mut announced = request(http::Method::GET, Body::empty());
+        announced.headers_mut().insert(
+            http::header::CONTENT_LENGTH,
+            http::header::HeaderValue::from_static("4"),
+        );
+        assert!(Replay::from(&announced).is_none());
+
+        let mut empty = request(http::Method::DELETE, Body::empty());
+        empty.headers_mut().insert(
+            http::header::CONTENT_LENGTH,
+            http::header::HeaderValue::from_static("0"),
+        );
+        assert!(Replay::from(&empty).is_some());
+    }
+}
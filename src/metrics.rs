@@ -0,0 +1,265 @@
+use super::gssapi;
+use super::gssapi_worker::PoolStats;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::fmt;
+use std::time::Duration;
+
+/// Route a response was proxied through, in the responses' extensions.
+#[derive(Debug, Clone)]
+pub struct RouteLabel(pub String);
+
+/// Everything exported on the metrics listener.
+pub struct Metrics {
+    registry: Registry,
+    handshakes_started: IntCounterVec,
+    handshakes_completed: IntCounterVec,
+    handshakes_failed: IntCounterVec,
+    requests: IntCounterVec,
+    backend_latency: HistogramVec,
+    active_connections: IntGauge,
+    gss_threads: IntGauge,
+    gss_contexts: IntGauge,
+    gss_queued: IntGauge,
+}
+
+// Exports the count kept by the gssapi module as it is at scrape time,
+// so there's no copy to keep in sync
+struct ReleaseFailures {
+    opts: Opts,
+    // Only there for its description
+    template: IntCounter,
+}
+
+impl ReleaseFailures {
+    fn new() -> Result<ReleaseFailures, prometheus::Error> {
+        let opts = Opts::new(
+            "spnego_proxy_gss_release_failures_total",
+            "GSS-API objects that couldn't be released",
+        );
+        Ok(ReleaseFailures {
+            template: IntCounter::with_opts(opts.clone())?,
+            opts,
+        })
+    }
+}
+
+impl Collector for ReleaseFailures {
+    fn desc(&self) -> Vec<&Desc> {
+        self.template.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // The options were checked when creating the template
+        let counter = IntCounter::with_opts(self.opts.clone()).unwrap();
+        counter.inc_by(gssapi::release_failures() as i64);
+        counter.collect()
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            handshakes_started: IntCounterVec::new(
+                Opts::new(
+                    "spnego_proxy_handshakes_started_total",
+                    "Authentication attempts started",
+                ),
+                &["method"],
+            )?,
+            handshakes_completed: IntCounterVec::new(
+                Opts::new(
+                    "spnego_proxy_handshakes_completed_total",
+                    "Successful authentications",
+                ),
+                &["method"],
+            )?,
+            handshakes_failed: IntCounterVec::new(
+                Opts::new(
+                    "spnego_proxy_handshakes_failed_total",
+                    "Failed authentications, by GSS-API major status",
+                ),
+                &["method", "major"],
+            )?,
+            requests: IntCounterVec::new(
+                Opts::new("spnego_proxy_requests_total", "Responses sent to clients"),
+                &["route", "status"],
+            )?,
+            backend_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "spnego_proxy_backend_latency_seconds",
+                    "Time until the backend's response headers",
+                ),
+                &["route"],
+            )?,
+            active_connections: IntGauge::new(
+                "spnego_proxy_active_connections",
+                "Open client connections",
+            )?,
+            gss_threads: IntGauge::new(
+                "spnego_proxy_gss_worker_threads",
+                "GSS-API worker threads still running",
+            )?,
+            gss_contexts: IntGauge::new(
+                "spnego_proxy_gss_contexts",
+                "Handshakes in progress on the worker threads",
+            )?,
+            gss_queued: IntGauge::new(
+                "spnego_proxy_gss_queued",
+                "Commands waiting for a worker thread",
+            )?,
+        };
+        let r = &metrics.registry;
+        r.register(Box::new(metrics.handshakes_started.clone()))?;
+        r.register(Box::new(metrics.handshakes_completed.clone()))?;
+        r.register(Box::new(metrics.handshakes_failed.clone()))?;
+        r.register(Box::new(metrics.requests.clone()))?;
+        r.register(Box::new(metrics.backend_latency.clone()))?;
+        r.register(Box::new(metrics.active_connections.clone()))?;
+        r.register(Box::new(metrics.gss_threads.clone()))?;
+        r.register(Box::new(metrics.gss_contexts.clone()))?;
+        r.register(Box::new(metrics.gss_queued.clone()))?;
+        r.register(Box::new(ReleaseFailures::new()?))?;
+        Ok(metrics)
+    }
+
+    pub fn handshake_started(&self, method: &str) {
+        self.handshakes_started.with_label_values(&[method]).inc();
+    }
+
+    pub fn handshake_completed(&self, method: &str) {
+        self.handshakes_completed.with_label_values(&[method]).inc();
+    }
+
+    pub fn handshake_failed(&self, method: &str, major: &str) {
+        self.handshakes_failed
+            .with_label_values(&[method, major])
+            .inc();
+    }
+
+    pub fn request(&self, route: &str, status: u16, backend_latency: Option<Duration>) {
+        self.requests
+            .with_label_values(&[route, &status.to_string()])
+            .inc();
+        if let Some(latency) = backend_latency {
+            let seconds = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) * 1e-9;
+            self.backend_latency
+                .with_label_values(&[route])
+                .observe(seconds);
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.inc();
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.dec();
+    }
+
+    /// The metrics in the text exposition format, with its content type.
+    /// The worker pool gauges are sampled now.
+    pub fn render(&self, pool: PoolStats) -> Result<(String, Vec<u8>), prometheus::Error> {
+        self.gss_threads.set(pool.live_threads as i64);
+        self.gss_contexts.set(pool.contexts as i64);
+        self.gss_queued.set(pool.queued as i64);
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        encoder.encode(&self.registry.gather(), &mut buffer)?;
+        Ok((String::from(encoder.format_type()), buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(metrics: &Metrics, pool: PoolStats) -> String {
+        let (content_type, body) = metrics.render(pool).unwrap();
+        assert!(content_type.starts_with("text/plain"), "{}", content_type);
+        String::from_utf8(body).unwrap()
+    }
+
+    fn has_line(text: &str, line: &str) -> bool {
+        text.lines().any(|l| l == line)
+    }
+
+    #[test]
+    fn renders_counters() {
+        let metrics = Metrics::new().unwrap();
+        metrics.handshake_started("negotiate");
+        metrics.handshake_started("negotiate");
+        metrics.handshake_completed("negotiate");
+        metrics.handshake_failed("basic", "DEFECTIVE_TOKEN");
+        metrics.request("/api", 200, Some(Duration::from_millis(1500)));
+        metrics.request("/api", 200, None);
+        metrics.request("none", 401, None);
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        let pool = PoolStats {
+            live_threads: 4,
+            contexts: 2,
+            queued: 1,
+        };
+        let text = render(&metrics, pool);
+        for line in &[
+            "spnego_proxy_handshakes_started_total{method=\"negotiate\"} 2",
+            "spnego_proxy_handshakes_completed_total{method=\"negotiate\"} 1",
+            "spnego_proxy_handshakes_failed_total{major=\"DEFECTIVE_TOKEN\",method=\"basic\"} 1",
+            "spnego_proxy_requests_total{route=\"/api\",status=\"200\"} 2",
+            "spnego_proxy_requests_total{route=\"none\",status=\"401\"} 1",
+            "spnego_proxy_backend_latency_seconds_count{route=\"/api\"} 1",
+            "spnego_proxy_backend_latency_seconds_sum{route=\"/api\"} 1.5",
+            "spnego_proxy_active_connections 1",
+            "spnego_proxy_gss_worker_threads 4",
+            "spnego_proxy_gss_contexts 2",
+            "spnego_proxy_gss_queued 1",
+        ] {
+            assert!(has_line(&text, line), "no {:?} in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn samples_pool_and_release_failures_at_render() {
+        let metrics = Metrics::new().unwrap();
+        let idle = PoolStats {
+            live_threads: 4,
+            contexts: 0,
+            queued: 0,
+        };
+        let text = render(&metrics, idle);
+        assert!(has_line(&text, "spnego_proxy_gss_contexts 0"), "{}", text);
+        let releases = format!(
+            "spnego_proxy_gss_release_failures_total {}",
+            gssapi::release_failures()
+        );
+        assert!(has_line(&text, &releases), "no {:?} in\n{}", releases, text);
+        assert!(text.contains("# TYPE spnego_proxy_gss_release_failures_total counter"));
+
+        let busy = PoolStats {
+            live_threads: 3,
+            contexts: 5,
+            queued: 7,
+        };
+        let text = render(&metrics, busy);
+        assert!(
+            has_line(&text, "spnego_proxy_gss_worker_threads 3"),
+            "{}",
+            text
+        );
+        assert!(has_line(&text, "spnego_proxy_gss_contexts 5"), "{}", text);
+        assert!(has_line(&text, "spnego_proxy_gss_queued 7"), "{}", text);
+    }
+}
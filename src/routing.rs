@@ -0,0 +1,295 @@
+use super::authorization::path_has_prefix;
+use super::upstream;
+use http::header::HOST;
+use http::Request;
+use std::str::FromStr;
+
+/// One entry of the routing table, sending matching requests to `backend`.
+///
+/// Textual form is `MATCH BACKEND [strip|NEW_PREFIX]`, where MATCH is
+/// `[HOST]/PATH_PREFIX` or just `HOST`, for example
+/// `wiki.example.com/api http://wiki-api:8080 strip`. HOST can start
+/// with `*.` to match all subdomains. BACKEND is a URL or `pool:NAME`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub backend: String,
+    /// Remove the matched prefix before passing the path to the backend
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replace the matched prefix with this one
+    pub rewrite_prefix: Option<String>,
+}
+
+impl Route {
+    /// Catch-all route to a single backend.
+    pub fn default_backend(backend: &str) -> Route {
+        Route {
+            host: None,
+            path_prefix: None,
+            backend: String::from(backend),
+            strip_prefix: false,
+            rewrite_prefix: None,
+        }
+    }
+
+    /// Short description of what the route matches, like
+    /// `wiki.example.com/api`, `*` for the catch-all.
+    pub fn label(&self) -> String {
+        match (&self.host, &self.path_prefix) {
+            (None, None) => String::from("*"),
+            (host, prefix) => format!(
+                "{}{}",
+                host.as_ref().map_or("", String::as_str),
+                prefix.as_ref().map_or("", String::as_str)
+            ),
+        }
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        self.host.as_ref().is_none_or(|pattern| {
+            host.is_some_and(|host| host_matches(pattern, host))
+        }) && self
+            .path_prefix
+            .as_ref()
+            .is_none_or(|prefix| path_has_prefix(path, prefix))
+    }
+
+    /// Path (and query) to request from the backend for `uri`.
+    pub fn backend_path(&self, uri: &http::Uri) -> String {
+        let path = uri.path();
+        let path = match self.path_prefix {
+            Some(ref prefix) if self.strip_prefix => replace_prefix(path, prefix, ""),
+            Some(ref prefix) => match self.rewrite_prefix {
+                Some(ref new_prefix) => replace_prefix(path, prefix, new_prefix),
+                None => String::from(path),
+            },
+            None => String::from(path),
+        };
+        match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        }
+    }
+
+    /// Problems with the route, for configuration validation.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if upstream::pool_name(&self.backend).is_none() {
+            if let Err(e) = upstream::check_url(&self.backend) {
+                errors.push(format!("backend: {}", e));
+            }
+        }
+        match self.path_prefix {
+            Some(ref prefix) if !prefix.starts_with('/') => {
+                errors.push(format!("path prefix {:?} must start with /", prefix))
+            }
+            None if self.strip_prefix || self.rewrite_prefix.is_some() => {
+                errors.push(String::from("prefix rewriting requires path_prefix"))
+            }
+            _ => (),
+        }
+        if self.strip_prefix && self.rewrite_prefix.is_some() {
+            errors.push(String::from(
+                "strip_prefix and rewrite_prefix can't be used together",
+            ));
+        }
+        errors
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Route, String> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(format!(
+                "Invalid route {:?}, expected [HOST]/PATH_PREFIX BACKEND [strip|NEW_PREFIX]",
+                s
+            ));
+        }
+        let (host, path_prefix) = match parts[0].find('/') {
+            Some(0) => (None, Some(parts[0])),
+            Some(i) => (Some(&parts[0][..i]), Some(&parts[0][i..])),
+            None => (Some(parts[0]), None),
+        };
+        let route = Route {
+            host: host.map(String::from),
+            path_prefix: path_prefix.map(String::from),
+            backend: String::from(parts[1]),
+            strip_prefix: parts.get(2) == Some(&"strip"),
+            rewrite_prefix: parts
+                .get(2)
+                .filter(|p| **p != "strip")
+                .map(|p| String::from(*p)),
+        };
+        match route.validate().into_iter().next() {
+            Some(e) => Err(format!("Invalid route {:?}: {}", s, e)),
+            None => Ok(route),
+        }
+    }
+}
+
+/// Routes are tried in order, the first matching one wins.
+#[derive(Debug)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// `default_backend` is used for requests no route matched.
+    pub fn new(mut routes: Vec<Route>, default_backend: Option<&str>) -> Router {
+        routes.extend(default_backend.map(Route::default_backend));
+        Router { routes }
+    }
+
+    /// Backends of all the routes.
+    pub fn backends(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|r| r.backend.as_str())
+    }
+
+    pub fn find<B>(&self, req: &Request<B>) -> Option<&Route> {
+        let host = request_host(req);
+        self.routes
+            .iter()
+            .find(|r| r.matches(host.as_deref(), req.uri().path()))
+    }
+}
+
+// Host the client asked for, without the port
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(HOST)?.to_str().ok()?,
+    };
+    let host = match host.rfind(':') {
+        // Not a part of an IPv6 address
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    Some(host.to_lowercase())
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern.starts_with("*.") {
+        host.ends_with(&pattern[1..].to_lowercase())
+    } else {
+        pattern.eq_ignore_ascii_case(host)
+    }
+}
+
+// "/app/x" with "/app" replaced by "/v2" is "/v2/x", by "" it's "/x"
+fn replace_prefix(path: &str, prefix: &str, new_prefix: &str) -> String {
+    let rest = &path[prefix.trim_end_matches('/').len()..];
+    let path = format!("{}{}", new_prefix.trim_end_matches('/'), rest);
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: &str, path: &str) -> Request<()> {
+        Request::get(path).header(HOST, host).body(()).unwrap()
+    }
+
+    fn backend_for<'a>(router: &'a Router, host: &str, path: &str) -> Option<&'a str> {
+        router
+            .find(&request(host, path))
+            .map(|r| r.backend.as_str())
+    }
+
+    fn rewritten(route: &str, path: &str) -> String {
+        let route: Route = route.parse().unwrap();
+        route.backend_path(&path.parse().unwrap())
+    }
+
+    #[test]
+    fn route_from_str() {
+        let route: Route = "wiki.example.com/api http://wiki-api:8080 strip"
+            .parse()
+            .unwrap();
+        assert_eq!(route.host.as_deref(), Some("wiki.example.com"));
+        assert_eq!(route.path_prefix.as_deref(), Some("/api"));
+        assert_eq!(route.backend, "http://wiki-api:8080");
+        assert!(route.strip_prefix);
+
+        let route: Route = "/app pool:apps /v2".parse().unwrap();
+        assert_eq!(route.host, None);
+        assert_eq!(route.rewrite_prefix.as_deref(), Some("/v2"));
+
+        assert!("/app".parse::<Route>().is_err());
+        assert!("/app http://a:1 strip extra".parse::<Route>().is_err());
+        assert!("wiki.example.com http://a:1 strip"
+            .parse::<Route>()
+            .is_err());
+    }
+
+    #[test]
+    fn backend_needs_scheme_and_host() {
+        assert!("/ localhost:3001".parse::<Route>().is_err());
+        assert!("/ /local/path".parse::<Route>().is_err());
+        assert!("/ http://".parse::<Route>().is_err());
+        assert!("/ http://localhost:3001".parse::<Route>().is_ok());
+        assert!("/ https://[::1]:3001/".parse::<Route>().is_ok());
+        assert!(Route::default_backend("localhost:3001").validate().len() == 1);
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let routes = vec![
+            "wiki.example.com/api http://api:1".parse().unwrap(),
+            "wiki.example.com http://wiki:1".parse().unwrap(),
+            "*.apps.example.com http://apps:1".parse().unwrap(),
+            "/static http://static:1".parse().unwrap(),
+        ];
+        let router = Router::new(routes, Some("http://default:1"));
+        let backend = |host, path| backend_for(&router, host, path);
+        assert_eq!(backend("wiki.example.com", "/api/x"), Some("http://api:1"));
+        assert_eq!(
+            backend("WIKI.example.com:443", "/api"),
+            Some("http://api:1")
+        );
+        assert_eq!(
+            backend("wiki.example.com", "/apiary"),
+            Some("http://wiki:1")
+        );
+        assert_eq!(backend("a.apps.example.com", "/"), Some("http://apps:1"));
+        assert_eq!(backend("apps.example.com", "/"), Some("http://default:1"));
+        assert_eq!(backend("other", "/static/a.css"), Some("http://static:1"));
+        assert_eq!(backend("other", "/"), Some("http://default:1"));
+
+        let router = Router::new(vec!["/static http://static:1".parse().unwrap()], None);
+        assert_eq!(backend_for(&router, "other", "/"), None);
+    }
+
+    #[test]
+    fn host_from_uri_or_header() {
+        let req = Request::get("http://Example.com:8080/").body(()).unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("example.com"));
+        let req = request("[::1]:8080", "/");
+        assert_eq!(request_host(&req).as_deref(), Some("[::1]"));
+        let req = request("[::1]", "/");
+        assert_eq!(request_host(&req).as_deref(), Some("[::1]"));
+        let req = Request::get("/").body(()).unwrap();
+        assert_eq!(request_host(&req), None);
+    }
+
+    #[test]
+    fn rewrites_prefixes() {
+        assert_eq!(rewritten("/app http://a:1", "/app/x?q=1"), "/app/x?q=1");
+        assert_eq!(rewritten("/app http://a:1 strip", "/app/x?q=1"), "/x?q=1");
+        assert_eq!(rewritten("/app http://a:1 strip", "/app"), "/");
+        assert_eq!(rewritten("/app/ http://a:1 strip", "/app/x"), "/x");
+        assert_eq!(rewritten("/app http://a:1 /v2", "/app/x"), "/v2/x");
+        assert_eq!(rewritten("/app http://a:1 /v2/", "/app"), "/v2");
+        assert_eq!(rewritten("/ http://a:1 /v2", "/x"), "/v2/x");
+    }
+}
@@ -0,0 +1,287 @@
+use super::identity::Identity;
+use hmac::{Hmac, Mac};
+use http::header::{HeaderMap, HeaderValue, COOKIE};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Signed session cookies, so that a client authenticated once doesn't
+/// need to repeat the Kerberos handshake on every new connection.
+///
+/// The value is `EXPIRY.PRINCIPAL.NAME.MAC`, with the principal, the
+/// forwarded name and MAC in URL-safe base64 and the MAC being HMAC-SHA256
+/// over `EXPIRY.PRINCIPAL.NAME`.
+#[derive(Debug)]
+pub struct SessionCookies {
+    key: Vec<u8>,
+    name: String,
+    lifetime: Duration,
+    secure: bool,
+}
+
+impl SessionCookies {
+    pub fn new(key: Vec<u8>, name: String, lifetime: Duration, secure: bool) -> SessionCookies {
+        SessionCookies {
+            key,
+            name,
+            lifetime,
+            secure,
+        }
+    }
+
+    /// `Set-Cookie` value for a freshly authenticated client, whose
+    /// session lasts for `valid_for` (if limited). The cookie doesn't
+    /// outlive it.
+    pub fn issue(&self, identity: &Identity, valid_for: Option<Duration>) -> HeaderValue {
+        let lifetime = valid_for.map_or(self.lifetime, |v| v.min(self.lifetime));
+        let expires = unix_now() + lifetime.as_secs();
+        let payload = format!(
+            "{}.{}.{}",
+            expires,
+            base64::encode_config(&identity.principal, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&identity.name, base64::URL_SAFE_NO_PAD)
+        );
+        let mac =
+            base64::encode_config(&self.mac(&payload).result().code(), base64::URL_SAFE_NO_PAD);
+        let mut cookie = format!(
+            "{}={}.{}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax",
+            self.name,
+            payload,
+            mac,
+            lifetime.as_secs()
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        // Only base64, digits and the configured name end up in there
+        HeaderValue::from_str(&cookie).unwrap()
+    }
+
+    /// Identity from a valid, unexpired session cookie in the request,
+    /// with the time left until the cookie expires. Groups aren't stored
+    /// in the cookie, they have to be looked up again.
+    pub fn verify(&self, headers: &HeaderMap) -> Option<(Identity, Duration)> {
+        cookie_values(headers, &self.name)
+            .into_iter()
+            .filter_map(|v| self.verify_value(&v))
+            .next()
+    }
+
+    /// Remove the session cookie, so that the backend never sees it.
+    pub fn strip(&self, headers: &mut HeaderMap) {
+        let remaining: Vec<String> = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .map(str::trim)
+            .filter(|c| !c.is_empty() && cookie_name(c) != self.name)
+            .map(String::from)
+            .collect();
+        headers.remove(COOKIE);
+        if !remaining.is_empty() {
+            if let Ok(v) = HeaderValue::from_str(&remaining.join("; ")) {
+                headers.insert(COOKIE, v);
+            }
+        }
+    }
+
+    fn verify_value(&self, value: &str) -> Option<(Identity, Duration)> {
+        let split = value.rfind('.')?;
+        let (payload, mac) = (&value[..split], &value[split + 1..]);
+        let mac = base64::decode_config(mac, base64::URL_SAFE_NO_PAD).ok()?;
+        self.mac(payload).verify(&mac).ok()?;
+
+        let mut parts = payload.splitn(3, '.');
+        let expires: u64 = parts.next()?.parse().ok()?;
+        let now = unix_now();
+        if expires <= now {
+            debug!("Session cookie expired");
+            return None;
+        }
+        let identity = Identity {
+            principal: decode_string(parts.next()?)?,
+            name: decode_string(parts.next()?)?,
+            groups: vec![],
+            delegated: None,
+        };
+        Some((identity, Duration::from_secs(expires - now)))
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).unwrap();
+        mac.input(payload.as_bytes());
+        mac
+    }
+}
+
+fn cookie_values(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| {
+            let mut parts = c.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(n), Some(v)) if n == name => Some(String::from(v)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn decode_string(raw: &str) -> Option<String> {
+    let bytes = base64::decode_config(raw, base64::URL_SAFE_NO_PAD).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn cookie_name(cookie: &str) -> &str {
+    cookie.split('=').next().unwrap_or("")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookies() -> SessionCookies {
+        SessionCookies::new(
+            b"0123456789abcdef".to_vec(),
+            String::from("session"),
+            Duration::from_secs(3600),
+            false,
+        )
+    }
+
+    fn alice() -> Identity {
+        Identity {
+            principal: String::from("alice@EXAMPLE.COM"),
+            name: String::from("alice"),
+            groups: vec![String::from("admins")],
+            delegated: None,
+        }
+    }
+
+    // The Cookie header a browser would send back for `set_cookie`
+    fn cookie_header(set_cookie: &HeaderValue) -> HeaderMap {
+        let cookie = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    // Cookie header with the value's `index`th dot-separated part replaced
+    fn tampered(set_cookie: &HeaderValue, index: usize, part: &str) -> HeaderMap {
+        let cookie = cookie_header(set_cookie);
+        let value = cookie[COOKIE]
+            .to_str()
+            .unwrap()
+            .trim_start_matches("session=");
+        let mut parts: Vec<&str> = value.split('.').collect();
+        parts[index] = part;
+        let mut headers = HeaderMap::new();
+        let cookie = format!("session={}", parts.join("."));
+        headers.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn round_trip() {
+        let cookies = cookies();
+        let set_cookie = cookies.issue(&alice(), None);
+        let (identity, left) = cookies.verify(&cookie_header(&set_cookie)).unwrap();
+        assert_eq!(identity.principal, "alice@EXAMPLE.COM");
+        assert_eq!(identity.name, "alice");
+        // Groups are looked up again
+        assert!(identity.groups.is_empty());
+        assert!(left <= Duration::from_secs(3600) && left >= Duration::from_secs(3590));
+    }
+
+    #[test]
+    fn attributes() {
+        let set_cookie = cookies().issue(&alice(), Some(Duration::from_secs(60)));
+        let set_cookie = set_cookie.to_str().unwrap();
+        assert!(set_cookie.contains("; Max-Age=60;"), "{}", set_cookie);
+        assert!(set_cookie.contains("; HttpOnly"));
+        assert!(set_cookie.contains("; SameSite=Lax"));
+        assert!(!set_cookie.contains("Secure"));
+
+        let secure = SessionCookies::new(vec![1], String::from("s"), Duration::from_secs(60), true);
+        let set_cookie = secure.issue(&alice(), None);
+        assert!(set_cookie.to_str().unwrap().ends_with("; Secure"));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let cookies = cookies();
+        let set_cookie = cookies.issue(&alice(), None);
+        let mallory = base64::encode_config("mallory@EXAMPLE.COM", base64::URL_SAFE_NO_PAD);
+        let root = base64::encode_config("root", base64::URL_SAFE_NO_PAD);
+        let far_future = (unix_now() + 1_000_000).to_string();
+        assert!(cookies
+            .verify(&tampered(&set_cookie, 0, &far_future))
+            .is_none());
+        assert!(cookies
+            .verify(&tampered(&set_cookie, 1, &mallory))
+            .is_none());
+        assert!(cookies.verify(&tampered(&set_cookie, 2, &root)).is_none());
+        assert!(cookies.verify(&tampered(&set_cookie, 3, "AAAA")).is_none());
+        assert!(cookies
+            .verify(&tampered(&set_cookie, 3, "not base64!"))
+            .is_none());
+
+        let other_key = SessionCookies::new(
+            b"another key".to_vec(),
+            String::from("session"),
+            Duration::from_secs(3600),
+            false,
+        );
+        assert!(other_key.verify(&cookie_header(&set_cookie)).is_none());
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let cookies = cookies();
+        let set_cookie = cookies.issue(&alice(), Some(Duration::from_secs(0)));
+        assert!(cookies.verify(&cookie_header(&set_cookie)).is_none());
+    }
+
+    #[test]
+    fn finds_cookie_among_others() {
+        let cookies = cookies();
+        let set_cookie = cookies.issue(&alice(), None);
+        let ours = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(
+            COOKIE,
+            HeaderValue::from_static("theme=dark; session=garbage"),
+        );
+        headers.append(
+            COOKIE,
+            HeaderValue::from_str(&format!("lang=en; {}", ours)).unwrap(),
+        );
+        assert!(cookies.verify(&headers).is_some());
+    }
+
+    #[test]
+    fn strips_only_the_session_cookie() {
+        let cookies = cookies();
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark; session=abc"));
+        headers.append(COOKIE, HeaderValue::from_static("sessionid=1;lang=en"));
+        cookies.strip(&mut headers);
+        assert_eq!(headers[COOKIE], "theme=dark; sessionid=1; lang=en");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("session=abc"));
+        cookies.strip(&mut headers);
+        assert!(headers.get(COOKIE).is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use futures::Future;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::prelude::Poll;
+use tokio::timer::Delay;
+
+const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// Number of requests currently handled on a connection.
+/// Shared between the connection's service and its `IdleTimeout`.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn start(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.0.clone())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+}
+
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Closes connections that stay quiet for too long between requests.
+///
+/// The clock is reset by any successful read or write, and doesn't run
+/// while a request is being handled (that's what the backend timeout is
+/// for). When it fires the client gets a best-effort 408 and the reads
+/// start failing, which makes hyper drop the connection.
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    deadline: Option<Delay>,
+    in_flight: InFlight,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Option<Duration>, in_flight: InFlight) -> IdleTimeout<S> {
+        IdleTimeout {
+            inner,
+            timeout: timeout.unwrap_or_default(),
+            deadline: timeout.map(|t| Delay::new(Instant::now() + t)),
+            in_flight,
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(ref mut deadline) = self.deadline {
+            deadline.reset(Instant::now() + self.timeout);
+        }
+    }
+}
+
+impl<S: Read + Write> IdleTimeout<S> {
+    fn poll_deadline(&mut self) -> io::Result<()> {
+        if !self.in_flight.is_idle() {
+            self.reset();
+            return Ok(());
+        }
+        let fired = match self.deadline {
+            Some(ref mut deadline) => deadline
+                .poll()
+                .map_err(io::Error::other)?
+                .is_ready(),
+            None => false,
+        };
+        if fired {
+            debug!("Closing idle connection");
+            let _ = self.inner.write(REQUEST_TIMEOUT_RESPONSE);
+            Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: Read + Write> Read for IdleTimeout<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.reset();
+                Ok(n)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.poll_deadline()?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for IdleTimeout<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.reset();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for IdleTimeout<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for IdleTimeout<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::Mutex;
+    use tokio::runtime::current_thread::Runtime;
+
+    // Reads what it's given once, then blocks; remembers what's written
+    struct Mock {
+        input: Vec<u8>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = self.input.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Stream = IdleTimeout<Mock>;
+
+    fn stream(timeout: Option<u64>, in_flight: InFlight) -> (Stream, Arc<Mutex<Vec<u8>>>) {
+        let written = Arc::new(Mutex::new(vec![]));
+        let mock = Mock {
+            input: vec![],
+            written: written.clone(),
+        };
+        let timeout = timeout.map(Duration::from_millis);
+        (IdleTimeout::new(mock, timeout, in_flight), written)
+    }
+
+    fn read(stream: &mut Stream) -> io::Result<usize> {
+        stream.read(&mut [0; 64])
+    }
+
+    // Wait `ms` on the runtime's timer, then run `f` on the stream
+    fn after<T, F>(ms: u64, stream: Stream, f: F) -> impl Future<Item = (Stream, T), Error = ()>
+    where
+        F: FnOnce(&mut Stream) -> T,
+    {
+        Delay::new(Instant::now() + Duration::from_millis(ms))
+            .map_err(|e| panic!("{}", e))
+            .map(move |()| {
+                let mut stream = stream;
+                let result = f(&mut stream);
+                (stream, result)
+            })
+    }
+
+    fn kind(r: io::Result<usize>) -> io::ErrorKind {
+        r.unwrap_err().kind()
+    }
+
+    #[test]
+    fn closes_idle_connection_with_408() {
+        let (mut stream, written) = stream(Some(50), InFlight::default());
+        let mut runtime = Runtime::new().unwrap();
+        let (_, closed) = runtime
+            .block_on(future::lazy(move || {
+                assert_eq!(kind(read(&mut stream)), io::ErrorKind::WouldBlock);
+                after(100, stream, |s| kind(read(s)))
+            }))
+            .unwrap();
+        assert_eq!(closed, io::ErrorKind::TimedOut);
+        assert_eq!(*written.lock().unwrap(), REQUEST_TIMEOUT_RESPONSE);
+    }
+
+    #[test]
+    fn paused_while_request_in_flight() {
+        let in_flight = InFlight::default();
+        let (stream, written) = stream(Some(50), in_flight.clone());
+        let request = in_flight.start();
+        let mut runtime = Runtime::new().unwrap();
+        let (_, (busy, idle_soon, idle_later)) = runtime
+            .block_on(future::lazy(move || {
+                after(100, stream, |s| kind(read(s)))
+                    .and_then(move |(stream, busy)| {
+                        drop(request);
+                        // The clock starts again when the request is done
+                        after(20, stream, move |s| (busy, kind(read(s))))
+                    })
+                    .and_then(|(stream, (busy, idle_soon))| {
+                        after(100, stream, move |s| (busy, idle_soon, kind(read(s))))
+                    })
+            }))
+            .unwrap();
+        assert_eq!(busy, io::ErrorKind::WouldBlock);
+        assert_eq!(idle_soon, io::ErrorKind::WouldBlock);
+        assert_eq!(idle_later, io::ErrorKind::TimedOut);
+        assert_eq!(*written.lock().unwrap(), REQUEST_TIMEOUT_RESPONSE);
+    }
+
+    #[test]
+    fn activity_resets_the_clock() {
+        let (stream, written) = stream(Some(100), InFlight::default());
+        let mut runtime = Runtime::new().unwrap();
+        let (_, (after_write, after_read)) = runtime
+            .block_on(future::lazy(move || {
+                after(70, stream, |s| s.write(b"response").unwrap())
+                    .and_then(|(stream, _)| after(70, stream, |s| kind(read(s))))
+                    .and_then(|(stream, after_write)| {
+                        after(0, stream, |s| s.inner.input.extend_from_slice(b"request"))
+                            .and_then(|(stream, ())| after(70, stream, |s| read(s).unwrap()))
+                            .and_then(move |(stream, _)| {
+                                after(70, stream, move |s| (after_write, kind(read(s))))
+                            })
+                    })
+            }))
+            .unwrap();
+        assert_eq!(after_write, io::ErrorKind::WouldBlock);
+        assert_eq!(after_read, io::ErrorKind::WouldBlock);
+        assert_eq!(*written.lock().unwrap(), b"response");
+    }
+
+    #[test]
+    fn no_timeout() {
+        let (stream, written) = stream(None, InFlight::default());
+        let mut runtime = Runtime::new().unwrap();
+        let (_, still_open) = runtime
+            .block_on(future::lazy(move || after(50, stream, |s| kind(read(s)))))
+            .unwrap();
+        assert_eq!(still_open, io::ErrorKind::WouldBlock);
+        assert!(written.lock().unwrap().is_empty());
+    }
+}
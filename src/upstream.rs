@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How a pool picks the upstream for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+/// A named set of interchangeable upstream servers, used by routes
+/// (and `backend`) as `pool:NAME`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSettings {
+    pub servers: Vec<String>,
+    pub balance: Balance,
+    /// Path polled on every server, without it servers are always healthy
+    pub health_check_path: Option<String>,
+    pub health_check_interval: u64,
+    pub health_check_timeout: u64,
+    /// Consecutive failed requests after which a server is ejected, 0 disables
+    pub max_fails: usize,
+    /// Seconds an ejected server gets no requests
+    pub fail_timeout: u64,
+}
+
+impl Default for PoolSettings {
+    fn default() -> PoolSettings {
+        PoolSettings {
+            servers: vec![],
+            balance: Balance::RoundRobin,
+            health_check_path: None,
+            health_check_interval: 10,
+            health_check_timeout: 5,
+            max_fails: 3,
+            fail_timeout: 30,
+        }
+    }
+}
+
+impl PoolSettings {
+    /// Problems with the pool, for configuration validation.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.servers.is_empty() {
+            errors.push(String::from("needs at least one server"));
+        }
+        for server in &self.servers {
+            if let Err(e) = check_url(server) {
+                errors.push(format!("servers: {}", e));
+            }
+        }
+        match self.health_check_path {
+            Some(ref path) if !path.starts_with('/') => {
+                errors.push(format!("health_check_path {:?} must start with /", path))
+            }
+            _ => (),
+        }
+        if self.health_check_interval == 0 || self.health_check_timeout == 0 {
+            errors.push(String::from(
+                "health_check_interval and health_check_timeout must be positive",
+            ));
+        }
+        errors
+    }
+}
+
+/// Checks that `url` is usable as a backend: it needs a scheme and a host.
+pub fn check_url(url: &str) -> Result<(), String> {
+    match url.parse::<http::Uri>() {
+        Ok(ref uri) if uri.scheme_part().is_some() && uri.authority_part().is_some() => Ok(()),
+        Ok(_) => Err(format!(
+            "URL {:?} needs a scheme and a host, like http://HOST:PORT",
+            url
+        )),
+        Err(e) => Err(format!("invalid URL {:?}: {}", url, e)),
+    }
+}
+
+/// Name of the pool a backend refers to, if it's `pool:NAME`.
+pub fn pool_name(backend: &str) -> Option<&str> {
+    backend.strip_prefix("pool:")
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub url: String,
+    healthy: AtomicBool,
+    // Requests in progress
+    active: AtomicUsize,
+    consecutive_failures: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn new(url: &str) -> Upstream {
+        Upstream {
+            url: String::from(url),
+            healthy: AtomicBool::new(true),
+            active: AtomicUsize::new(0),
+            consecutive_failures: AtomicUsize::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if until > Instant::now() => false,
+            Some(_) => {
+                info!("Upstream {} back from ejection", self.url);
+                *ejected_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Full URL for a request path.
+    pub fn url_for(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+}
+
+/// Counts the request as active on its upstream while alive.
+pub struct ActiveRequest<'a>(&'a Upstream);
+
+impl<'a> Drop for ActiveRequest<'a> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub settings: PoolSettings,
+    pub upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    fn new(name: &str, settings: PoolSettings) -> Pool {
+        Pool {
+            name: String::from(name),
+            upstreams: settings.servers.iter().map(|s| Upstream::new(s)).collect(),
+            settings,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // A lone backend URL, always used: there's nothing to fail over to
+    fn single(url: &str) -> Pool {
+        Pool::new(
+            url,
+            PoolSettings {
+                servers: vec![String::from(url)],
+                max_fails: 0,
+                ..PoolSettings::default()
+            },
+        )
+    }
+
+    /// Pick an available upstream, skipping the ones already `tried`.
+    pub fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        let n = self.upstreams.len();
+        let mut candidates = (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|i| !tried.contains(i) && self.upstreams[*i].is_available());
+        match self.settings.balance {
+            Balance::RoundRobin => candidates.next(),
+            Balance::LeastConnections => {
+                candidates.min_by_key(|i| self.upstreams[*i].active.load(Ordering::SeqCst))
+            }
+        }
+    }
+
+    pub fn start(&self, i: usize) -> ActiveRequest<'_> {
+        self.upstreams[i].active.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(&self.upstreams[i])
+    }
+
+    pub fn report_success(&self, i: usize) {
+        self.upstreams[i]
+            .consecutive_failures
+            .store(0, Ordering::SeqCst);
+    }
+
+    /// Count a failed request, ejecting the upstream after too many of them.
+    pub fn report_failure(&self, i: usize) {
+        let upstream = &self.upstreams[i];
+        let failures = upstream.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.settings.max_fails > 0 && failures >= self.settings.max_fails {
+            warn!(
+                "Ejecting upstream {} of pool {} after {} failures",
+                upstream.url, self.name, failures
+            );
+            upstream.consecutive_failures.store(0, Ordering::SeqCst);
+            *upstream.ejected_until.lock().unwrap() =
+                Some(Instant::now() + Duration::from_secs(self.settings.fail_timeout));
+        }
+    }
+
+    /// Record the result of an active health check.
+    pub fn set_healthy(&self, i: usize, healthy: bool) {
+        let upstream = &self.upstreams[i];
+        let was_healthy = upstream.healthy.swap(healthy, Ordering::SeqCst);
+        if was_healthy && !healthy {
+            warn!("Upstream {} of pool {} is down", upstream.url, self.name);
+        } else if !was_healthy && healthy {
+            info!("Upstream {} of pool {} is up", upstream.url, self.name);
+        }
+    }
+}
+
+/// All the pools, keyed by the backend string routes refer to them with:
+/// `pool:NAME` for configured pools, or the URL for a lone backend.
+#[derive(Debug)]
+pub struct Upstreams {
+    pools: HashMap<String, Pool>,
+}
+
+impl Upstreams {
+    pub fn new<'a, I>(settings: &BTreeMap<String, PoolSettings>, backends: I) -> Upstreams
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut pools: HashMap<String, Pool> = settings
+            .iter()
+            .map(|(name, s)| (format!("pool:{}", name), Pool::new(name, s.clone())))
+            .collect();
+        for backend in backends {
+            if !pools.contains_key(backend) {
+                pools.insert(String::from(backend), Pool::single(backend));
+            }
+        }
+        Upstreams { pools }
+    }
+
+    pub fn get(&self, backend: &str) -> Option<&Pool> {
+        self.pools.get(backend)
+    }
+
+    /// Pools with active health checks.
+    pub fn checked(&self) -> impl Iterator<Item = &Pool> {
+        self.pools
+            .values()
+            .filter(|p| p.settings.health_check_path.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balance: Balance, servers: usize) -> Pool {
+        Pool::new(
+            "test",
+            PoolSettings {
+                servers: (0..servers).map(|i| format!("http://s{}:80", i)).collect(),
+                balance,
+                max_fails: 2,
+                ..PoolSettings::default()
+            },
+        )
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(Balance::RoundRobin, 3);
+        let picked: Vec<_> = (0..6).map(|_| pool.pick(&[]).unwrap()).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let pool = pool(Balance::LeastConnections, 3);
+        let _first = pool.start(0);
+        let _second = pool.start(1);
+        let _third = pool.start(1);
+        assert_eq!(pool.pick(&[]), Some(2));
+        let busy = vec![pool.start(2), pool.start(2)];
+        assert_eq!(pool.pick(&[]), Some(0));
+        // Finished requests don't count
+        drop(busy);
+        assert_eq!(pool.pick(&[]), Some(2));
+    }
+
+    #[test]
+    fn skips_tried() {
+        let round_robin = pool(Balance::RoundRobin, 3);
+        for _ in 0..3 {
+            assert_eq!(round_robin.pick(&[0, 2]), Some(1));
+        }
+        assert_eq!(round_robin.pick(&[0, 1, 2]), None);
+
+        let least_connections = pool(Balance::LeastConnections, 2);
+        let _busy = least_connections.start(1);
+        assert_eq!(least_connections.pick(&[0]), Some(1));
+    }
+
+    #[test]
+    fn ejects_after_max_fails() {
+        let pool = pool(Balance::RoundRobin, 2);
+        pool.report_failure(0);
+        // A success resets the count
+        pool.report_success(0);
+        pool.report_failure(0);
+        assert!(pool.upstreams[0].is_available());
+        pool.report_failure(0);
+        assert!(!pool.upstreams[0].is_available());
+        for _ in 0..4 {
+            assert_eq!(pool.pick(&[]), Some(1));
+        }
+        assert_eq!(pool.pick(&[1]), None);
+    }
+
+    #[test]
+    fn recovers_after_fail_timeout() {
+        let pool = pool(Balance::RoundRobin, 1);
+        pool.report_failure(0);
+        pool.report_failure(0);
+        assert_eq!(pool.pick(&[]), None);
+        // As if fail_timeout had passed
+        *pool.upstreams[0].ejected_until.lock().unwrap() =
+            Some(Instant::now() - Duration::from_secs(1));
+        assert_eq!(pool.pick(&[]), Some(0));
+        // And it needs max_fails new failures to be ejected again
+        pool.report_failure(0);
+        assert_eq!(pool.pick(&[]), Some(0));
+    }
+
+    #[test]
+    fn never_ejects_without_max_fails() {
+        let pool = Pool::single("http://backend:80");
+        for _ in 0..10 {
+            pool.report_failure(0);
+        }
+        assert_eq!(pool.pick(&[]), Some(0));
+    }
+
+    #[test]
+    fn skips_unhealthy() {
+        let pool = pool(Balance::LeastConnections, 2);
+        pool.set_healthy(0, false);
+        assert_eq!(pool.pick(&[]), Some(1));
+        assert_eq!(pool.pick(&[1]), None);
+        pool.set_healthy(0, true);
+        assert_eq!(pool.pick(&[1]), Some(0));
+    }
+
+    #[test]
+    fn lone_backends_get_pools() {
+        let mut settings = BTreeMap::new();
+        settings.insert(String::from("apps"), pool(Balance::RoundRobin, 2).settings);
+        let upstreams = Upstreams::new(&settings, vec!["pool:apps", "http://other:80"]);
+        assert_eq!(upstreams.get("pool:apps").unwrap().upstreams.len(), 2);
+        let other = upstreams.get("http://other:80").unwrap();
+        assert_eq!(other.upstreams[0].url_for("/x?y"), "http://other:80/x?y");
+        assert!(upstreams.get("pool:missing").is_none());
+    }
+}
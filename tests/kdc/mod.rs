@@ -0,0 +1,383 @@
+//! Throwaway Kerberos realm, proxy and backend for the integration tests.
+//!
+//! Realms need the MIT KDC tools (krb5kdc, kdb5_util, kadmin.local), so the
+//! tests using one are `#[ignore]`d by default.
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const REALM: &str = "TEST.SPNEGO";
+pub const SERVICE: &str = "HTTP@localhost";
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// Where distributions put the KDC tools, which often isn't in PATH
+const SBIN_DIRS: &[&str] = &["/usr/sbin", "/usr/local/sbin", "/usr/lib/mit/sbin", "/sbin"];
+
+fn find_tool(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain(SBIN_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn wait_for_port(addr: SocketAddr, what: &str) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        if Instant::now() > deadline {
+            panic!("{} didn't start listening on {}", what, addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// A KDC with its own database, configuration and keytab in a temporary
+/// directory, all removed when it's dropped.
+pub struct Realm {
+    pub dir: PathBuf,
+    pub krb5_conf: PathBuf,
+    kdc_conf: PathBuf,
+    pub keytab: PathBuf,
+    kadmin: PathBuf,
+    kdc: Child,
+}
+
+impl Realm {
+    /// Create and start the realm, or `None` if there's no KDC to run.
+    pub fn start() -> Option<Realm> {
+        let krb5kdc = find_tool("krb5kdc")?;
+        let kdb5_util = find_tool("kdb5_util")?;
+        let kadmin = find_tool("kadmin.local")?;
+
+        let dir = env::temp_dir().join(format!(
+            "spnego-proxy-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let port = free_port();
+        let krb5_conf = dir.join("krb5.conf");
+        let kdc_conf = dir.join("kdc.conf");
+        fs::write(
+            &krb5_conf,
+            format!(
+                "[libdefaults]
+ default_realm = {realm}
+ dns_lookup_kdc = false
+ dns_lookup_realm = false
+ dns_canonicalize_hostname = false
+ rdns = false
+ clockskew = 1
+ default_ccache_name = FILE:{dir}/ccache
+[realms]
+ {realm} = {{
+  kdc = 127.0.0.1:{port}
+ }}
+[domain_realm]
+ localhost = {realm}
+ other.localhost = {realm}
+",
+                realm = REALM,
+                dir = dir.display(),
+                port = port
+            ),
+        )
+        .unwrap();
+        fs::write(
+            &kdc_conf,
+            format!(
+                "[kdcdefaults]
+ kdc_ports = {port}
+ kdc_tcp_ports = {port}
+[realms]
+ {realm} = {{
+  database_name = {dir}/principal
+  key_stash_file = {dir}/stash
+  acl_file = {dir}/kadm5.acl
+  max_life = 1h
+ }}
+[logging]
+ kdc = FILE:{dir}/kdc.log
+",
+                realm = REALM,
+                dir = dir.display(),
+                port = port
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("kadm5.acl"), "").unwrap();
+
+        let status = Command::new(kdb5_util)
+            .args(["-r", REALM, "-P", "master-password", "create", "-s"])
+            .env("KRB5_CONFIG", &krb5_conf)
+            .env("KRB5_KDC_PROFILE", &kdc_conf)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "kdb5_util create failed");
+
+        let kdc = Command::new(krb5kdc)
+            .args(["-n", "-r", REALM])
+            .env("KRB5_CONFIG", &krb5_conf)
+            .env("KRB5_KDC_PROFILE", &kdc_conf)
+            .spawn()
+            .unwrap();
+        let realm = Realm {
+            keytab: dir.join("proxy.keytab"),
+            dir,
+            krb5_conf,
+            kdc_conf,
+            kadmin,
+            kdc,
+        };
+        wait_for_port(([127, 0, 0, 1], port).into(), "krb5kdc");
+        Some(realm)
+    }
+
+    fn kadmin(&self, query: &str) {
+        let status = Command::new(&self.kadmin)
+            .args(["-r", REALM, "-q", query])
+            .env("KRB5_CONFIG", &self.krb5_conf)
+            .env("KRB5_KDC_PROFILE", &self.kdc_conf)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "kadmin.local -q {:?} failed", query);
+    }
+
+    /// Add a user principal, `options` are passed to addprinc.
+    pub fn add_user(&self, name: &str, password: &str, options: &str) {
+        self.kadmin(&format!("addprinc -pw {} {} {}", password, options, name));
+    }
+
+    /// Add a service principal, and its key to the proxy's keytab if `in_keytab`.
+    pub fn add_service(&self, name: &str, in_keytab: bool) {
+        self.kadmin(&format!("addprinc -randkey {}", name));
+        if in_keytab {
+            self.kadmin(&format!("ktadd -k {} {}", self.keytab.display(), name));
+        }
+    }
+
+    /// Point this process' GSS-API calls at the realm.
+    pub fn use_for_client(&self) {
+        env::set_var("KRB5_CONFIG", &self.krb5_conf);
+        env::set_var(
+            "KRB5CCNAME",
+            format!("FILE:{}", self.dir.join("ccache").display()),
+        );
+    }
+}
+
+impl Drop for Realm {
+    fn drop(&mut self) {
+        let _ = self.kdc.kill();
+        let _ = self.kdc.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Backend answering every request with the request's head as the body.
+pub fn start_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let head = read_head(&mut stream);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                head.len(),
+                head
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    addr
+}
+
+fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = vec![];
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte) {
+            Ok(1) => head.push(byte[0]),
+            _ => break,
+        }
+    }
+    String::from_utf8_lossy(&head).into_owned()
+}
+
+fn proxy_binary() -> PathBuf {
+    if let Some(path) = option_env!("CARGO_BIN_EXE_spnego-proxy") {
+        return PathBuf::from(path);
+    }
+    // target/debug/deps/this-test -> target/debug/spnego-proxy
+    let exe = env::current_exe().unwrap();
+    exe.parent()
+        .and_then(Path::parent)
+        .unwrap()
+        .join("spnego-proxy")
+}
+
+/// The proxy, accepting `SERVICE` with the realm's keytab.
+pub struct Proxy {
+    pub addr: SocketAddr,
+    child: Child,
+}
+
+impl Proxy {
+    pub fn start(realm: &Realm, backend: SocketAddr) -> Proxy {
+        Proxy::start_with(realm, backend, &[])
+    }
+
+    /// Start the proxy with additional command line flags.
+    pub fn start_with(realm: &Realm, backend: SocketAddr, flags: &[&str]) -> Proxy {
+        let log = fs::File::create(realm.dir.join("proxy.log")).unwrap();
+        let mut command = Command::new(proxy_binary());
+        command
+            .arg("--service-principal")
+            .arg(SERVICE)
+            .arg("--keytab")
+            .arg(&realm.keytab)
+            .arg("-vvv")
+            .args(flags)
+            .env("KRB5_CONFIG", &realm.krb5_conf)
+            .env("KRB5RCACHEDIR", &realm.dir)
+            .stderr(log);
+        Proxy::spawn(command, backend)
+    }
+
+    /// Start the proxy without a realm, for requests that never get to
+    /// the GSS-API. Every handshake fails.
+    pub fn start_without_kdc(backend: SocketAddr, flags: &[&str]) -> Proxy {
+        let mut command = Command::new(proxy_binary());
+        command.args(flags).stderr(Stdio::null());
+        Proxy::spawn(command, backend)
+    }
+
+    fn spawn(mut command: Command, backend: SocketAddr) -> Proxy {
+        let addr: SocketAddr = ([127, 0, 0, 1], free_port()).into();
+        let child = command
+            .arg("--bind")
+            .arg(addr.to_string())
+            .arg("--backend")
+            .arg(format!("http://{}", backend))
+            .spawn()
+            .unwrap();
+        let proxy = Proxy { addr, child };
+        wait_for_port(addr, "spnego-proxy");
+        proxy
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub head: String,
+    pub body: String,
+}
+
+/// GET `path` on a new connection, optionally with an Authorization header.
+pub fn get(addr: SocketAddr, path: &str, authorization: Option<&str>) -> Response {
+    let mut stream = connect(addr);
+    stream
+        .write_all(request(path, authorization, "close").as_bytes())
+        .unwrap();
+
+    let mut raw = String::new();
+    stream.read_to_string(&mut raw).unwrap();
+    let split = raw.find("\r\n\r\n").expect("no end of the response head");
+    let head = String::from(&raw[..split]);
+    let body = String::from(&raw[split + 4..]);
+    Response {
+        status: status(&head),
+        head,
+        body,
+    }
+}
+
+/// Keep-alive connection, for what the proxy remembers per connection.
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    pub fn open(addr: SocketAddr) -> Connection {
+        Connection {
+            stream: connect(addr),
+        }
+    }
+
+    /// GET `path`, optionally with an Authorization header.
+    pub fn get(&mut self, path: &str, authorization: Option<&str>) -> Response {
+        self.stream
+            .write_all(request(path, authorization, "keep-alive").as_bytes())
+            .unwrap();
+        let head = read_head(&mut self.stream);
+        let head = String::from(head.trim_end());
+        let length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map_or(0, |(_, value)| value.trim().parse().unwrap());
+        let mut body = vec![0; length];
+        self.stream.read_exact(&mut body).unwrap();
+        Response {
+            status: status(&head),
+            head,
+            body: String::from_utf8(body).unwrap(),
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+}
+
+fn request(path: &str, authorization: Option<&str>, connection: &str) -> String {
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: {}\r\n",
+        path, connection
+    );
+    if let Some(value) = authorization {
+        request.push_str(&format!("Authorization: {}\r\n", value));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+fn status(head: &str) -> u16 {
+    head.split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .expect("no status in the response")
+}
@@ -0,0 +1,45 @@
+//! Requests the proxy answers, or passes on, without a handshake, so
+//! without a KDC.
+
+#[allow(dead_code)]
+mod kdc;
+
+#[test]
+fn malformed_negotiate_is_rejected() {
+    let proxy = kdc::Proxy::start_without_kdc(kdc::start_backend(), &[]);
+    for value in &["Negotiate", "Negotiate not-base64!"] {
+        let response = kdc::get(proxy.addr, "/", Some(value));
+        assert_eq!(response.status, 400, "{}: {}", value, response.head);
+    }
+}
+
+#[test]
+fn malformed_authorization_on_public_path() {
+    let proxy =
+        kdc::Proxy::start_without_kdc(kdc::start_backend(), &["--public-path", "/static/*"]);
+    let response = kdc::get(proxy.addr, "/static/a.css", Some("Negotiate not-base64!"));
+    assert_eq!(response.status, 200, "{}", response.head);
+    // Still meant for the proxy, so not passed on
+    assert!(
+        !response.body.contains("authorization:"),
+        "{}",
+        response.body
+    );
+
+    let response = kdc::get(proxy.addr, "/static/a.css", Some("Basic Zm9v"));
+    assert_eq!(response.status, 200, "{}", response.head);
+    assert!(
+        response.body.contains("authorization: Basic Zm9v"),
+        "{}",
+        response.body
+    );
+}
+
+#[test]
+fn malformed_basic_without_basic_auth() {
+    let proxy = kdc::Proxy::start_without_kdc(kdc::start_backend(), &[]);
+    // The header is the backend's, the proxy only asks for its own
+    let response = kdc::get(proxy.addr, "/", Some("Basic Zm9v"));
+    assert_eq!(response.status, 401, "{}", response.head);
+    assert!(response.head.contains("www-authenticate: Negotiate"));
+}
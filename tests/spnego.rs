@@ -0,0 +1,205 @@
+//! Full SPNEGO handshakes through the proxy, against a local MIT KDC.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+#[allow(dead_code)]
+#[path = "../src/gssapi.rs"]
+mod gssapi;
+#[allow(dead_code)]
+mod kdc;
+
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+lazy_static! {
+    // The client side's krb5 configuration comes from the environment,
+    // so tests can't run concurrently
+    static ref SERIAL: Mutex<()> = Mutex::new(());
+}
+
+struct Setup {
+    proxy: kdc::Proxy,
+    realm: kdc::Realm,
+    _serial: MutexGuard<'static, ()>,
+}
+
+// Realm with alice (and the proxy's service in its keytab), and a proxy
+// in front of the echo backend
+fn setup() -> Setup {
+    let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let realm =
+        kdc::Realm::start().expect("MIT KDC tools (krb5kdc, kdb5_util, kadmin.local) not found");
+    realm.add_service("HTTP/localhost", true);
+    realm.add_user("alice", "alice-password", "");
+    realm.use_for_client();
+    let proxy = kdc::Proxy::start(&realm, kdc::start_backend());
+    Setup {
+        proxy,
+        realm,
+        _serial: serial,
+    }
+}
+
+// First token of a handshake with `service`, as `principal`
+fn initial_token(principal: &str, password: &str, service: &str) -> Vec<u8> {
+    initial_token_with_flags(
+        principal,
+        password,
+        service,
+        gssapi::ContextFlags::default(),
+    )
+}
+
+fn initial_token_with_flags(
+    principal: &str,
+    password: &str,
+    service: &str,
+    flags: gssapi::ContextFlags,
+) -> Vec<u8> {
+    let user = gssapi::GSSName::import_principal(principal).unwrap();
+    let cred = gssapi::GSSCredential::acquire_with_password(&user, password).unwrap();
+    let target = gssapi::GSSName::import_service(service).unwrap();
+    let mut ctx = gssapi::GSSContext::new();
+    match gssapi::init_sec_context(&mut ctx, Some(&cred), &target, flags, None).unwrap() {
+        gssapi::InitResult::ContinueNeeded(buf) | gssapi::InitResult::Complete(buf) => {
+            Vec::from(buf.as_bytes())
+        }
+    }
+}
+
+fn negotiate(token: &[u8]) -> String {
+    format!("Negotiate {}", base64::encode(token))
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn challenges_without_credentials() {
+    let setup = setup();
+    let response = kdc::get(setup.proxy.addr, "/", None);
+    assert_eq!(response.status, 401);
+    assert!(response.head.contains("www-authenticate: Negotiate"));
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn successful_handshake() {
+    let setup = setup();
+    let token = initial_token("alice", "alice-password", kdc::SERVICE);
+    let response = kdc::get(setup.proxy.addr, "/hello", Some(&negotiate(&token)));
+    assert_eq!(response.status, 200, "{}", response.head);
+    assert!(response.body.starts_with("GET /hello HTTP/1.1"));
+    let user = format!("x-remote-user: alice@{}", kdc::REALM);
+    assert!(response.body.contains(&user), "{}", response.body);
+    // The client's token isn't passed on
+    assert!(!response.body.contains("authorization:"));
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn wrong_service() {
+    let setup = setup();
+    // Known to the KDC, but not in the proxy's keytab
+    setup.realm.add_service("HTTP/other.localhost", false);
+    let token = initial_token("alice", "alice-password", "HTTP@other.localhost");
+    let response = kdc::get(setup.proxy.addr, "/", Some(&negotiate(&token)));
+    assert_eq!(response.status, 401);
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn expired_ticket() {
+    let setup = setup();
+    setup
+        .realm
+        .add_user("shortlived", "short-password", "-maxlife \"3 seconds\"");
+    let token = initial_token("shortlived", "short-password", kdc::SERVICE);
+    // The realm allows a second of clock skew
+    thread::sleep(Duration::from_secs(5));
+    let response = kdc::get(setup.proxy.addr, "/", Some(&negotiate(&token)));
+    assert_eq!(response.status, 401);
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn session_expires_with_ticket() {
+    let setup = setup();
+    setup
+        .realm
+        .add_user("shortlived", "short-password", "-maxlife \"4 seconds\"");
+    let mut connection = kdc::Connection::open(setup.proxy.addr);
+    let token = initial_token("shortlived", "short-password", kdc::SERVICE);
+    let response = connection.get("/", Some(&negotiate(&token)));
+    assert_eq!(response.status, 200, "{}", response.head);
+    let response = connection.get("/", None);
+    assert_eq!(response.status, 200, "{}", response.head);
+
+    // Past the ticket's end, and the realm's clock skew
+    thread::sleep(Duration::from_secs(6));
+    let response = connection.get("/", None);
+    assert_eq!(response.status, 401);
+    assert!(response.head.contains("www-authenticate: Negotiate"));
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn missing_required_flag() {
+    let setup = setup();
+    // The client doesn't ask for mutual authentication at first
+    let strict = kdc::Proxy::start_with(
+        &setup.realm,
+        kdc::start_backend(),
+        &["--require-flag", "mutual"],
+    );
+    let mut connection = kdc::Connection::open(strict.addr);
+    let token = initial_token("alice", "alice-password", kdc::SERVICE);
+    let response = connection.get("/", Some(&negotiate(&token)));
+    assert_eq!(response.status, 401);
+    assert!(response.head.contains("www-authenticate: Negotiate"));
+
+    // The rejected context doesn't stick to the connection
+    let token = initial_token_with_flags(
+        "alice",
+        "alice-password",
+        kdc::SERVICE,
+        gssapi::ContextFlags::MUTUAL,
+    );
+    let response = connection.get("/", Some(&negotiate(&token)));
+    assert_eq!(response.status, 200, "{}", response.head);
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn session_cookie_limited_by_session_lifetime() {
+    let setup = setup();
+    let proxy = kdc::Proxy::start_with(
+        &setup.realm,
+        kdc::start_backend(),
+        &["--session-cookie", "--session-lifetime", "60"],
+    );
+    let token = initial_token("alice", "alice-password", kdc::SERVICE);
+    let response = kdc::get(proxy.addr, "/", Some(&negotiate(&token)));
+    assert_eq!(response.status, 200, "{}", response.head);
+    let max_age: u64 = response
+        .head
+        .split("Max-Age=")
+        .nth(1)
+        .and_then(|rest| rest.split(';').next())
+        .and_then(|age| age.parse().ok())
+        .expect("no session cookie");
+    assert!(max_age <= 60, "cookie lives for {}s", max_age);
+}
+
+#[test]
+#[ignore = "needs the MIT KDC tools, run with --ignored"]
+fn replayed_token() {
+    let setup = setup();
+    let token = initial_token("alice", "alice-password", kdc::SERVICE);
+    let first = kdc::get(setup.proxy.addr, "/", Some(&negotiate(&token)));
+    assert_eq!(first.status, 200, "{}", first.head);
+    let replayed = kdc::get(setup.proxy.addr, "/", Some(&negotiate(&token)));
+    assert_eq!(replayed.status, 401);
+}